@@ -0,0 +1,109 @@
+use super::fs::Fs;
+use anyhow::Result;
+use std::path::Path;
+
+/// 位图
+///
+/// 每个位表示一个分片是否失效，
+/// 内存中完整保存所有位，
+/// 修改时只写入被修改的64位.
+pub struct Bitmap {
+    words: Vec<u64>,
+    file: Fs,
+}
+
+impl Bitmap {
+    /// 打开位图文件
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Bitmap;
+    ///
+    /// let bitmap = Bitmap::new("./.static/1.free").unwrap();
+    /// ```
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = Fs::new(path)?;
+        let size = file.stat()?.len() as usize / 8;
+        let mut buffer = vec![0u8; size * 8];
+        file.intact_read(&mut buffer, 0)?;
+        let words = buffer
+            .chunks(8)
+            .map(|x| u64::from_be_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]))
+            .collect();
+        Ok(Self { words, file })
+    }
+
+    /// 标记位
+    pub fn insert(&mut self, bit: u64) -> Result<()> {
+        let word = bit as usize / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+
+        self.words[word] |= 1 << (bit % 64);
+        self.write(word)
+    }
+
+    /// 清除位
+    pub fn remove(&mut self, bit: u64) -> Result<()> {
+        let word = bit as usize / 64;
+        if word < self.words.len() {
+            self.words[word] &= !(1 << (bit % 64));
+            self.write(word)?;
+        }
+
+        Ok(())
+    }
+
+    /// 获取第一个被标记的位
+    pub fn first(&self) -> Option<u64> {
+        self.words
+            .iter()
+            .position(|x| *x != 0)
+            .map(|i| i as u64 * 64 + self.words[i].trailing_zeros() as u64)
+    }
+
+    /// 获取全部被标记的位
+    ///
+    /// 结果按照从小到大排列
+    pub fn bits(&self) -> Vec<u64> {
+        let mut bits = Vec::new();
+        for (i, word) in self.words.iter().enumerate() {
+            let mut word = *word;
+            while word != 0 {
+                bits.push(i as u64 * 64 + word.trailing_zeros() as u64);
+                word &= word - 1;
+            }
+        }
+
+        bits
+    }
+
+    /// 重置位图
+    ///
+    /// 清除所有位之后标记给定的位，
+    /// 并重写整个位图文件
+    pub fn reset(&mut self, bits: &[u64]) -> Result<()> {
+        let size = bits.iter().max().map(|x| *x as usize / 64 + 1).unwrap_or(0);
+        self.words = vec![0; size];
+        for bit in bits {
+            self.words[*bit as usize / 64] |= 1 << (bit % 64);
+        }
+
+        let mut buffer = Vec::with_capacity(size * 8);
+        for word in self.words.iter() {
+            buffer.extend_from_slice(&word.to_be_bytes());
+        }
+
+        self.file.write(&buffer, 0)?;
+        self.file.truncate(buffer.len() as u64)?;
+        self.file.flush()
+    }
+
+    /// 写入单个64位
+    fn write(&mut self, word: usize) -> Result<()> {
+        self.file.write(&self.words[word].to_be_bytes(), word as u64 * 8)?;
+        self.file.flush()
+    }
+}
@@ -1,19 +1,78 @@
 use super::KernelOptions;
 use std::rc::Rc;
 use bytes::{
-    BufMut, 
-    Bytes, 
+    BufMut,
+    Bytes,
     BytesMut
 };
 
+/// 分片固定头长度
+pub const HEADER_SIZE: usize = 29;
+
+/// 分片标记：数据的头部分片
+pub const FLAG_HEAD: u8 = 1;
+
+/// 分片标记：分片数据为空，
+/// 用于区分长度为0的空分片和写满的分片，
+/// 由编码器根据数据长度设置
+pub const FLAG_EMPTY: u8 = 1 << 1;
+
+/// 分片标记：分片已经失效，
+/// 只在位图模式下设置，
+/// 位图文件损坏时用于重建位图
+pub const FLAG_FREE: u8 = 1 << 2;
+
+/// 当前版本认识的全部分片标记
+pub const FLAG_KNOWN: u8 = FLAG_HEAD | FLAG_EMPTY | FLAG_FREE;
+
+/// 分片
+///
+/// `next` 下个分片所在的轨道和位置
+/// `generation` 分片复用代数，每次从失效链表复用时递增
+/// `flags` 分片标记，参考`FLAG_*`，其余位保留给以后使用，编解码时原样保留
+/// `hits` 头部分片记录的数据读取次数
+/// `accessed` 头部分片记录的最后读取时间(毫秒时间戳)
+/// `data` 分片数据
+#[derive(Clone, Debug)]
+pub struct Chunk<'a> {
+    pub next: Option<(u16, u64)>,
+    pub generation: u16,
+    pub flags: u8,
+    pub hits: u32,
+    pub accessed: u64,
+    pub data: &'a [u8],
+}
+
+/// 分片头
+///
+/// 分片除数据以外的固定字段，
+/// `size`为分片数据长度
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub next: Option<(u16, u64)>,
+    pub generation: u16,
+    pub flags: u8,
+    pub hits: u32,
+    pub accessed: u64,
+    pub size: usize,
+}
+
+impl Header {
+    /// 是否为数据的头部分片
+    pub fn is_head(&self) -> bool {
+        self.flags & FLAG_HEAD != 0
+    }
+}
+
 /// 分片编解码器
 ///
 /// 将分片编码为缓冲区
 /// 或者将缓冲区解码为分片.
 ///
 /// #### diff_size
-/// 分片内部最大数据长度，分片固定头长度为17，
-/// 所以这里使用分片长度减去17.
+/// 分片内部最大数据长度，分片固定头长度为29，
+/// 所以这里使用分片长度减去29.
+#[derive(Clone)]
 pub struct Codec {
     chunk_size: usize,
     diff_size: usize,
@@ -29,7 +88,7 @@ impl Codec {
     /// use std::rc::Rc;
     ///
     /// let options = Rc::new(KernelOptions::from(
-    ///     Path::new("./.static"), 
+    ///     Path::new("./.static"),
     ///     1024 * 1024 * 1024 * 1
     /// ));
     ///
@@ -37,7 +96,7 @@ impl Codec {
     /// ````
     pub fn new(options: Rc<KernelOptions>) -> Self {
         Self {
-            diff_size: (options.chunk_size - 10) as usize,
+            diff_size: options.chunk_size as usize - HEADER_SIZE,
             chunk_size: options.chunk_size as usize,
         }
     }
@@ -47,40 +106,53 @@ impl Codec {
     /// # Examples
     ///
     /// ```no_run
-    /// use super::{Chunk, Codec, KernelOptions};
+    /// use super::{Chunk, Codec, KernelOptions, FLAG_HEAD};
     /// use std::rc::Rc;
-    /// use bytes::Bytes;
     ///
     /// let chunk = Chunk {
-    ///     next: Some(17),
-    ///     data: Bytes::from_static(b"hello"),
+    ///     next: Some((1, 4120)),
+    ///     generation: 0,
+    ///     flags: FLAG_HEAD,
+    ///     hits: 0,
+    ///     accessed: 0,
+    ///     data: b"hello",
     /// };
     ///
     /// let options = Rc::new(KernelOptions::from(
-    ///     Path::new("./.static"), 
+    ///     Path::new("./.static"),
     ///     1024 * 1024 * 1024 * 1
     /// ));
     ///
     /// let codec = Codec::new(options);
-    /// let packet = codec.encoder(chunk.clone());
+    /// let packet = codec.encoder(&chunk);
     /// ```
     #[rustfmt::skip]
-    pub fn encoder(&self, next_offset: Option<u64>, chunk: &[u8]) -> Bytes {
+    pub fn encoder(&self, chunk: &Chunk) -> Bytes {
         let mut packet = BytesMut::new();
 
-        let size = match chunk.len() == self.diff_size {
-            false => chunk.len() as u16,
+        let size = match chunk.data.len() == self.diff_size {
+            false => chunk.data.len() as u32,
             true => 0,
         };
 
-        let next = match next_offset {
+        let mut flags = chunk.flags & !FLAG_EMPTY;
+        if chunk.data.is_empty() {
+            flags |= FLAG_EMPTY;
+        }
+
+        let (next_track, next) = match chunk.next {
             Some(next) => next,
-            None => 0,
+            None => (0, 0),
         };
 
         packet.put_u64(next);
-        packet.put_u16(size);
-        packet.extend_from_slice(&chunk);
+        packet.put_u32(size);
+        packet.put_u16(next_track);
+        packet.put_u16(chunk.generation);
+        packet.put_u8(flags);
+        packet.put_u32(chunk.hits);
+        packet.put_u64(chunk.accessed);
+        packet.extend_from_slice(chunk.data);
 
         if packet.len() < self.chunk_size {
             packet.resize(self.chunk_size, 0);
@@ -94,30 +166,78 @@ impl Codec {
     /// # Examples
     ///
     /// ```no_run
-    /// use super::{Chunk, Codec, KernelOptions};
+    /// use super::{Chunk, Codec, KernelOptions, FLAG_HEAD};
     /// use std::rc::Rc;
-    /// use bytes::Bytes;
     ///
     /// let chunk = Chunk {
-    ///     next: Some(17),
-    ///     data: Bytes::from_static(b"hello"),
+    ///     next: Some((1, 4120)),
+    ///     generation: 0,
+    ///     flags: FLAG_HEAD,
+    ///     hits: 0,
+    ///     accessed: 0,
+    ///     data: b"hello",
     /// };
     ///
     /// let options = Rc::new(KernelOptions::from(
-    ///     Path::new("./.static"), 
+    ///     Path::new("./.static"),
     ///     1024 * 1024 * 1024 * 1
     /// ));
     ///
     /// let codec = Codec::new(options);
-    /// let packet = codec.encoder(chunk.clone());
-    /// let result = codec.decoder(packet.clone());
+    /// let packet = codec.encoder(&chunk);
+    /// let result = codec.decoder(&packet);
     ///
     /// assert_eq!(result.next, chunk.next);
     /// assert_eq!(result.data, chunk.data);
     /// ```
+    pub fn decoder<'a>(&self, chunk: &'a [u8]) -> Chunk<'a> {
+        let header = self.header(chunk);
+        let end_offset = header.size + HEADER_SIZE;
+        assert!(end_offset <= chunk.len());
+        Chunk {
+            data: &chunk[HEADER_SIZE..end_offset],
+            generation: header.generation,
+            accessed: header.accessed,
+            hits: header.hits,
+            flags: header.flags,
+            next: header.next,
+        }
+    }
+
+    /// 解码分片头
+    ///
+    /// 只需要分片头长度的缓冲区，
+    /// 用于不需要分片数据的遍历
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Chunk, Codec, KernelOptions, FLAG_HEAD};
+    /// use std::rc::Rc;
+    ///
+    /// let chunk = Chunk {
+    ///     next: Some((1, 4120)),
+    ///     generation: 0,
+    ///     flags: FLAG_HEAD,
+    ///     hits: 0,
+    ///     accessed: 0,
+    ///     data: b"hello",
+    /// };
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let codec = Codec::new(options);
+    /// let packet = codec.encoder(&chunk);
+    /// let header = codec.header(&packet[..HEADER_SIZE]);
+    ///
+    /// assert_eq!(header.size, 5);
+    /// ```
     #[rustfmt::skip]
-    pub fn decoder<'a>(&self, chunk: &'a [u8]) -> (Option<u64>, &'a [u8]) {
-        assert!(chunk.len() > 10);
+    pub fn header(&self, chunk: &[u8]) -> Header {
+        assert!(chunk.len() >= HEADER_SIZE);
         let source_next = u64::from_be_bytes([
             chunk[0],
             chunk[1],
@@ -129,27 +249,123 @@ impl Codec {
             chunk[7],
         ]);
 
-        let source_size = u16::from_be_bytes([
+        let source_size = u32::from_be_bytes([
             chunk[8],
-            chunk[9]
+            chunk[9],
+            chunk[10],
+            chunk[11]
         ]) as usize;
 
-        let end_offset = match source_size {
+        let next_track = u16::from_be_bytes([
+            chunk[12],
+            chunk[13]
+        ]);
+
+        let generation = u16::from_be_bytes([
+            chunk[14],
+            chunk[15]
+        ]);
+
+        let hits = u32::from_be_bytes([
+            chunk[17],
+            chunk[18],
+            chunk[19],
+            chunk[20]
+        ]);
+
+        let accessed = u64::from_be_bytes([
+            chunk[21],
+            chunk[22],
+            chunk[23],
+            chunk[24],
+            chunk[25],
+            chunk[26],
+            chunk[27],
+            chunk[28],
+        ]);
+
+        let flags = chunk[16];
+        let size = match source_size {
+            0 if flags & FLAG_EMPTY != 0 => 0,
             0 => self.diff_size,
             _ => source_size,
-        } + 10;
-
-        assert!(end_offset <= chunk.len());
-        let data = &chunk[10..end_offset];
+        };
 
         let next = match source_next == 0 {
-            false => Some(source_next),
+            false => Some((next_track, source_next)),
             true => None,
         };
 
-        (
+        Header {
+            flags,
+            generation,
+            accessed,
+            hits,
             next,
-            data
-        )
+            size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, Codec, KernelOptions, FLAG_EMPTY, FLAG_HEAD, HEADER_SIZE};
+    use std::rc::Rc;
+
+    fn codec() -> Codec {
+        Codec::new(Rc::new(KernelOptions::from("./.static".to_string(), 1024 * 1024)))
+    }
+
+    fn chunk(data: &[u8], flags: u8) -> Chunk<'_> {
+        Chunk { next: None, generation: 0, flags, hits: 0, accessed: 0, data }
+    }
+
+    #[test]
+    fn empty_and_full_chunks_round_trip() {
+        let codec = codec();
+        let full = vec![7u8; 4096 - HEADER_SIZE];
+        for data in [&[][..], &b"hello"[..], &full[..]] {
+            let packet = codec.encoder(&chunk(data, FLAG_HEAD));
+            assert_eq!(packet.len(), 4096);
+            let decoded = codec.decoder(&packet);
+            assert_eq!(decoded.data, data);
+            assert_eq!(decoded.flags & FLAG_EMPTY != 0, data.is_empty());
+            assert_eq!(codec.header(&packet).size, data.len());
+        }
+
+        // 非空数据不会保留外部传入的空分片标记
+        let packet = codec.encoder(&chunk(b"hello", FLAG_EMPTY));
+        assert_eq!(codec.decoder(&packet).flags, 0);
+    }
+
+    #[test]
+    fn reserved_flags_are_preserved() {
+        let codec = codec();
+        for bit in (0..8).map(|x| 1u8 << x) {
+            for data in [&[][..], &b"hello"[..]] {
+                let packet = codec.encoder(&Chunk {
+                    next: Some((2, 4136)),
+                    generation: 7,
+                    flags: bit,
+                    hits: 3,
+                    accessed: 99,
+                    data,
+                });
+
+                // 空分片标记总是由数据长度决定，其他标记位原样保留
+                let flags = match data.is_empty() {
+                    true => bit | FLAG_EMPTY,
+                    false => bit & !FLAG_EMPTY,
+                };
+
+                let header = codec.header(&packet);
+                assert_eq!(header.flags, flags, "bit {:#04x}", bit);
+                assert_eq!(header.is_head(), bit == FLAG_HEAD);
+                assert_eq!(header.next, Some((2, 4136)));
+                assert_eq!((header.generation, header.hits, header.accessed), (7, 3, 99));
+                assert_eq!(header.size, data.len());
+                assert_eq!(codec.decoder(&packet).flags, flags);
+            }
+        }
     }
 }
@@ -0,0 +1,222 @@
+use super::{Kernel, KernelOptions};
+use std::io::{Read, Write};
+use anyhow::{anyhow, Result};
+
+/// 存储集群
+///
+/// 将多个存储核心组成统一的键值接口，
+/// 根据键的哈希选择存储核心，
+/// 使用最高随机权重哈希(rendezvous hashing)，
+/// 每个存储核心和键计算权重，选择权重最大的核心，
+/// 所以增加存储核心时只有分配给新核心的数据需要迁移.
+///
+/// 存储核心的编号为加入集群的顺序，
+/// 所以重新打开集群时需要保持相同的顺序.
+pub struct Cluster {
+    nodes: Vec<Kernel>,
+}
+
+impl Cluster {
+    /// 创建集群
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Cluster, KernelOptions};
+    ///
+    /// let cluster = Cluster::new(vec![
+    ///     KernelOptions::from("./.static/0".to_string(), 1024 * 1024 * 1024 * 1),
+    ///     KernelOptions::from("./.static/1".to_string(), 1024 * 1024 * 1024 * 1),
+    /// ]).unwrap();
+    /// ```
+    pub fn new(options: Vec<KernelOptions>) -> Result<Self> {
+        if options.is_empty() {
+            return Err(anyhow!("empty cluster"));
+        }
+
+        let mut nodes = Vec::with_capacity(options.len());
+        for item in options {
+            nodes.push(Kernel::with_options(item)?);
+        }
+
+        Ok(Self { nodes })
+    }
+
+    /// 获取键所在的存储核心编号
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Cluster, KernelOptions};
+    ///
+    /// let cluster = Cluster::new(vec![
+    ///     KernelOptions::from("./.static/0".to_string(), 1024 * 1024 * 1024 * 1),
+    ///     KernelOptions::from("./.static/1".to_string(), 1024 * 1024 * 1024 * 1),
+    /// ]).unwrap();
+    ///
+    /// let node = cluster.route(b"test");
+    /// ```
+    pub fn route(&self, key: &[u8]) -> usize {
+        route(self.nodes.len(), key)
+    }
+
+    /// 写入数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Cluster, KernelOptions};
+    ///
+    /// let mut cluster = Cluster::new(vec![
+    ///     KernelOptions::from("./.static/0".to_string(), 1024 * 1024 * 1024 * 1),
+    ///     KernelOptions::from("./.static/1".to_string(), 1024 * 1024 * 1024 * 1),
+    /// ]).unwrap();
+    ///
+    /// let file = std::fs::File::open("test.mp4").unwrap();
+    /// cluster.put(b"test", file).unwrap();
+    /// ```
+    pub fn put(&mut self, key: &[u8], stream: impl Read) -> Result<()> {
+        let node = self.route(key);
+        self.nodes[node].write(key, stream)
+    }
+
+    /// 读取数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Cluster, KernelOptions};
+    ///
+    /// let mut cluster = Cluster::new(vec![
+    ///     KernelOptions::from("./.static/0".to_string(), 1024 * 1024 * 1024 * 1),
+    ///     KernelOptions::from("./.static/1".to_string(), 1024 * 1024 * 1024 * 1),
+    /// ]).unwrap();
+    ///
+    /// let file = std::fs::File::create("test.mp4").unwrap();
+    /// cluster.get(b"test", file).unwrap();
+    /// ```
+    pub fn get(&mut self, key: &[u8], stream: impl Write) -> Result<()> {
+        let node = self.route(key);
+        self.nodes[node].read(key, stream)
+    }
+
+    /// 删除数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Cluster, KernelOptions};
+    ///
+    /// let mut cluster = Cluster::new(vec![
+    ///     KernelOptions::from("./.static/0".to_string(), 1024 * 1024 * 1024 * 1),
+    ///     KernelOptions::from("./.static/1".to_string(), 1024 * 1024 * 1024 * 1),
+    /// ]).unwrap();
+    ///
+    /// cluster.delete(b"test").unwrap();
+    /// ```
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let node = self.route(key);
+        self.nodes[node].delete(key)
+    }
+
+    /// 增加存储核心
+    ///
+    /// 新核心加入之后重新平衡，
+    /// 将路由到新核心的数据从原来的核心转移过去，
+    /// 返回转移的数据数量
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Cluster, KernelOptions};
+    ///
+    /// let mut cluster = Cluster::new(vec![
+    ///     KernelOptions::from("./.static/0".to_string(), 1024 * 1024 * 1024 * 1),
+    /// ]).unwrap();
+    ///
+    /// let moved = cluster.add(
+    ///     KernelOptions::from("./.static/1".to_string(), 1024 * 1024 * 1024 * 1)
+    /// ).unwrap();
+    /// ```
+    pub fn add(&mut self, options: KernelOptions) -> Result<usize> {
+        let mut target = Kernel::with_options(options)?;
+        let node = self.nodes.len();
+        let mut moved = 0;
+
+        // 增加核心只会让键路由到新核心，
+        // 所以只需要检查和新核心的权重
+        for kernel in self.nodes.iter_mut() {
+            for key in kernel.keys() {
+                if route(node + 1, &key) == node {
+                    kernel.transfer(&key, &mut target)?;
+                    moved += 1;
+                }
+            }
+        }
+
+        self.nodes.push(target);
+        Ok(moved)
+    }
+}
+
+/// 计算键所在的存储核心编号
+fn route(nodes: usize, key: &[u8]) -> usize {
+    (0..nodes)
+        .max_by_key(|node| weight(*node as u64, key))
+        .unwrap_or(0)
+}
+
+/// 计算存储核心和键的权重
+///
+/// 使用FNV-1a哈希并且混合结果，
+/// 保证不同版本之间结果一致
+fn weight(node: u64, key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in node.to_be_bytes().iter().chain(key) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cluster, KernelOptions};
+
+    #[test]
+    fn add_moves_keys_only_to_new_node() {
+        let dirs = (0..3).map(|_| tempfile::tempdir().unwrap()).collect::<Vec<_>>();
+        let options = |i: usize| KernelOptions::from(dirs[i].path().to_str().unwrap().to_string(), 1024 * 1024);
+        let mut cluster = Cluster::new(vec![options(0), options(1)]).unwrap();
+        let keys = (0..200u32).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>();
+        let mut counts = [0; 2];
+        for key in keys.iter() {
+            let node = cluster.route(key);
+            assert_eq!(node, cluster.route(key));
+            counts[node] += 1;
+            cluster.put(key, &key[..]).unwrap();
+        }
+
+        assert!(counts.iter().all(|count| *count > 50));
+        let before = keys.iter().map(|key| cluster.route(key)).collect::<Vec<_>>();
+        let moved = cluster.add(options(2)).unwrap();
+        assert!(moved > 30 && moved < 110);
+        assert_eq!(moved, keys.iter().filter(|key| cluster.route(key) == 2).count());
+
+        // 新增节点之后数据只会迁移到新节点
+        for (key, node) in keys.iter().zip(before) {
+            let now = cluster.route(key);
+            assert!(now == node || now == 2);
+            let mut out = Vec::new();
+            cluster.get(key, &mut out).unwrap();
+            assert_eq!(&out, key);
+        }
+
+        cluster.delete(&keys[0]).unwrap();
+        assert!(cluster.get(&keys[0], Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::Result;
+use std::io::{
+    Error,
+    ErrorKind,
+    Read
+};
+
+/// 导入数据流
+///
+/// 从归档中读取单个数据的分段，
+/// 读取到结束分段之后始终返回0
+pub struct Segments<'a, R> {
+    stream: &'a mut R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<'a, R: Read> Segments<'a, R> {
+    /// 创建导入数据流
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Segments;
+    /// use std::fs::File;
+    ///
+    /// let mut file = File::open("backup.archive").unwrap();
+    /// let segments = Segments::new(&mut file);
+    /// ```
+    pub fn new(stream: &'a mut R) -> Self {
+        Self {
+            remaining: 0,
+            done: false,
+            stream,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for Segments<'a, R> {
+    #[rustfmt::skip]
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // 当前分段读取完成
+        // 读取下个分段的长度，长度为0表示数据结束
+        if self.remaining == 0 {
+            let mut size = [0u8; 4];
+            self.stream.read_exact(&mut size)?;
+            self.remaining = u32::from_be_bytes(size) as usize;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let size = std::cmp::min(buf.len(), self.remaining);
+        self.stream.read_exact(&mut buf[..size])?;
+        self.remaining -= size;
+        Ok(size)
+    }
+}
+
+/// 读取归档数据头
+///
+/// 返回源头部分片所在的轨道和位置，
+/// 归档结束时返回`None`
+#[rustfmt::skip]
+pub fn read_head(stream: &mut impl Read) -> Result<Option<(u16, u64)>> {
+    let mut buffer = [0u8; 10];
+    let mut size = 0;
+
+    // 在数据头之前结束表示归档结束，
+    // 在数据头中间结束表示归档不完整
+    while size < buffer.len() {
+        match stream.read(&mut buffer[size..])? {
+            0 if size == 0 => return Ok(None),
+            0 => return Err(Error::from(ErrorKind::UnexpectedEof).into()),
+            n => size += n,
+        }
+    }
+
+    let mut track = [0u8; 2];
+    let mut index = [0u8; 8];
+    track.copy_from_slice(&buffer[..2]);
+    index.copy_from_slice(&buffer[2..]);
+    Ok(Some((
+        u16::from_be_bytes(track),
+        u64::from_be_bytes(index)
+    )))
+}
@@ -0,0 +1,146 @@
+use super::{Fs, KernelError, KernelOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Result;
+
+/// 无法确认持有锁的进程是否存在时，
+/// 锁文件超过这个时间没有修改视为失效
+const STALE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// 目录锁
+///
+/// 在存储目录中独占创建`.lock`文件，
+/// 保证同一时间只有一个实例打开目录，
+/// 锁文件内容为持有锁的进程编号，
+/// 释放时只删除自己创建的锁文件.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// 获取目录锁
+    ///
+    /// 锁文件已经存在时返回`KernelError::AlreadyLocked`，
+    /// 开启`force_lock`时只接管已经失效的锁，
+    /// 即持有锁的进程已经退出，
+    /// 或者无法确认进程是否存在并且锁文件超过`STALE_TIMEOUT`没有修改
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Lock, KernelOptions};
+    ///
+    /// let options = KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// let lock = Lock::acquire(&options).unwrap();
+    /// ```
+    pub fn acquire(options: &KernelOptions) -> Result<Self> {
+        let path: &Path = options.path.as_ref();
+        let path = path.join(".lock");
+        let mut file = match Fs::create_new(&path)? {
+            Some(file) => file,
+            None if options.force_lock && is_stale(&path)? => {
+                // 删除失效的锁之后重新独占创建，
+                // 同时接管的其他实例只有一个能创建成功
+                if let Err(e) = std::fs::remove_file(&path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+
+                match Fs::create_new(&path)? {
+                    Some(file) => file,
+                    None => return Err(KernelError::AlreadyLocked.into()),
+                }
+            },
+            None => return Err(KernelError::AlreadyLocked.into()),
+        };
+
+        let pid = std::process::id().to_string();
+        file.write(pid.as_bytes(), 0)?;
+        file.truncate(pid.len() as u64)?;
+        file.flush()?;
+        Ok(Self { path })
+    }
+}
+
+/// 检查锁文件是否已经失效
+///
+/// 可以读取进程列表时检查持有锁的进程是否存在，
+/// 否则检查锁文件的修改时间
+fn is_stale(path: &Path) -> Result<bool> {
+    let content = std::fs::read_to_string(path)?;
+    let proc = Path::new("/proc");
+    if let Ok(pid) = content.trim().parse::<u32>() {
+        if proc.exists() {
+            return Ok(!proc.join(pid.to_string()).exists());
+        }
+    }
+
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok(modified.elapsed().map(|x| x > STALE_TIMEOUT).unwrap_or(false))
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let pid = std::process::id().to_string();
+        if std::fs::read_to_string(&self.path).ok().as_deref() == Some(pid.as_str()) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KernelError, KernelOptions, Lock};
+    use std::path::Path;
+
+    fn options(path: &Path, force_lock: bool) -> KernelOptions {
+        let mut options = KernelOptions::from(path.to_str().unwrap().to_string(), 1024 * 1024);
+        options.force_lock = force_lock;
+        options
+    }
+
+    fn locked(error: anyhow::Error) -> bool {
+        error.downcast_ref::<KernelError>() == Some(&KernelError::AlreadyLocked)
+    }
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lock::acquire(&options(dir.path(), false)).unwrap();
+        assert!(locked(Lock::acquire(&options(dir.path(), false)).err().unwrap()));
+        drop(lock);
+        assert!(!dir.path().join(".lock").exists());
+        Lock::acquire(&options(dir.path(), false)).unwrap();
+    }
+
+    #[test]
+    fn force_lock_only_takes_over_dead_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".lock");
+        let live = Lock::acquire(&options(dir.path(), false)).unwrap();
+        assert!(locked(Lock::acquire(&options(dir.path(), true)).err().unwrap()));
+        std::mem::forget(live);
+
+        // 持有锁的进程已经退出
+        std::fs::write(&path, "4000000000").unwrap();
+        let lock = Lock::acquire(&options(dir.path(), true)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn drop_keeps_another_owners_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".lock");
+        let lock = Lock::acquire(&options(dir.path(), false)).unwrap();
+        std::fs::write(&path, "4000000000").unwrap();
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "4000000000");
+    }
+}
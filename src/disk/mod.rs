@@ -1,33 +1,89 @@
-
+pub mod archive;
+pub mod lock;
+pub mod objects;
+pub mod quota;
 pub mod reader;
+pub mod ring;
+pub mod verify;
 pub mod writer;
 
 use super::fs::readdir;
+use super::legacy;
 use std::io::{Read, Write};
-use writer::{Writer, Callback};
-use reader::Reader;
-use anyhow::Result;
+use writer::{Writer, Callback, State};
+use objects::{ObjectId, Objects};
+use verify::{Limiter, Task, VerifyReport};
+use ring::RingBuffer;
+use archive::Segments;
+use lock::Lock;
+use quota::{Quota, QuotaDecision};
+use reader::{Lease, Reader, Readers, ReadStream};
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::cmp::Reverse;
 use std::{
-    collections::HashMap,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
     cell::RefCell, 
+    sync::Mutex,
+    path::Path,
     rc::Rc
 };
 
 pub use super::{
-    index::AllocMap,
-    track::Track,
+    chunk::{Codec, Header, HEADER_SIZE},
+    error::KernelError,
+    track::{Track, TRACK_HEADER_SIZE},
+    fs::Fs,
     KernelOptions
 };
 
 /// 轨道列表
 pub type Tracks = Rc<RefCell<HashMap<u16, Track>>>;
 
+/// 头部分片所在的轨道和位置
+pub type Head = (u16, u64);
+
+/// 被迁移的头部分片的原位置和新位置
+pub type Moves = Vec<(Head, Head)>;
+
+/// 压缩进度回调间隔(分片数)
+const PROGRESS_INTERVAL: usize = 64;
+
+/// 轨道压缩计划
+///
+/// `preds` 指向目标轨道分片的前驱分片  
+/// `moves` 迁移的分片原位置、新位置以及是否为头部分片  
+/// `holes` 迁移之后剩余的失效分片
+struct CompactPlan {
+    preds: HashMap<u64, (u16, u64)>,
+    moves: Vec<(u64, u64, bool)>,
+    holes: Vec<u64>,
+}
+
+/// 数据读取统计
+///
+/// `hits` 读取次数  
+/// `accessed` 最后读取时间(毫秒时间戳)，从未读取为0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectStats {
+    pub hits: u32,
+    pub accessed: u64,
+}
+
 /// 内部存储
 ///
-/// 管理所有轨道的读取和写入
+/// 管理所有轨道的读取和写入，
+/// 目录锁在所有轨道关闭之后释放
 pub struct Disk {
     options: Rc<KernelOptions>,
+    replica: Option<Box<Disk>>,
+    objects: Option<Objects>,
+    quota: Option<Quota>,
+    readers: Rc<RefCell<Readers>>,
+    last_active: Instant,
     tracks: Tracks,
+    lock: Option<Lock>,
 }
 
 impl Disk {
@@ -49,10 +105,61 @@ impl Disk {
     pub fn new(options: Rc<KernelOptions>) -> Self {
         Self {
             tracks: Rc::new(RefCell::new(HashMap::new())),
+            readers: Rc::new(RefCell::new(Readers::default())),
+            last_active: Instant::now(),
+            objects: None,
+            replica: None,
+            quota: None,
+            lock: None,
             options,
         }
     }
 
+    /// 设置副本
+    ///
+    /// 之后的写入、删除、数据ID和压缩操作都会在副本上重放，
+    /// 因为分配策略是确定的，所以副本的数据位置和数据ID与主存储一致，
+    /// 结果不一致时返回`KernelError::ReplicaDiverged`，
+    /// 副本必须已经初始化并且和当前存储具有相同的数据
+    ///
+    /// 普通写入按块同时写入两边，
+    /// 批量写入和分段并行写入需要将数据完整读入内存，
+    /// 副本写入失败或者结果不一致时删除当前存储中刚写入的数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let mut disk = Disk::new(Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// )));
+    ///
+    /// let mut replica = Disk::new(Rc::new(KernelOptions::from(
+    ///     Path::new("./.replica"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// )));
+    ///
+    /// disk.init().unwrap();
+    /// replica.init().unwrap();
+    /// disk.set_replica(replica).unwrap();
+    /// ```
+    pub fn set_replica(&mut self, mut replica: Disk) -> Result<()> {
+        if self.heads()? != replica.heads()? {
+            return Err(KernelError::ReplicaDiverged.into());
+        }
+
+        self.replica = Some(Box::new(replica));
+        Ok(())
+    }
+
+    /// 取出副本
+    pub fn take_replica(&mut self) -> Option<Disk> {
+        self.replica.take().map(|replica| *replica)
+    }
+
     /// 初始化
     ///
     /// 必须对该实例调用初始化，
@@ -74,8 +181,19 @@ impl Disk {
     /// ```
     #[rustfmt::skip]
     pub fn init(&mut self) -> Result<()> {
+        self.options.validate()?;
         let mut track_count: i32 = 0;
 
+        // 开启目录锁时先获取锁，
+        // 其他实例已经打开目录时返回错误
+        if self.options.lock && self.lock.is_none() {
+            self.lock = Some(Lock::acquire(&self.options)?);
+        }
+
+        // 隔离旧版本的轨道文件，
+        // 打开索引之后再迁移数据
+        legacy::isolate(&self.options.path)?;
+
         // 读取目录的所有轨道文件，
         // 将找到的轨道索引创建为轨道类，
         // 并推入内部轨道列表
@@ -83,7 +201,7 @@ impl Disk {
             if let Ok(name) = dir?.file_name().into_string() {
                 if name.ends_with(".track") {
                     if let Ok(track_id) = name.replace(".track", "").parse::<u16>() {
-                        self.create_track(track_id)?;
+                        self.open_track(track_id)?;
                         track_count += 1;
                     }
                 }
@@ -97,16 +215,19 @@ impl Disk {
             self.create_track(1)?;
         }
 
+        self.objects = Some(Objects::new(&self.options)?);
         Ok(())
     }
 
     /// 打开读取流
     ///
+    /// 从给定的头部分片开始，
+    /// 沿着分片链表读取全部数据
+    ///
     /// # Examples
     ///
     /// ```no_run
     /// use super::{Disk, KernelOptions};
-    /// use std::collections::HashMap;
     /// use std::fs::File;
     /// use std::rc::Rc;
     /// 
@@ -118,37 +239,180 @@ impl Disk {
     /// let mut disk = Disk::new(options);
     /// disk.init().unwrap();
     ///
-    /// let mut file = File::open("test.mp4");
-    /// disk.read(file, HashMap::new()).unwrap();
+    /// let mut file = File::create("test.mp4").unwrap();
+    /// disk.read(file, 1, 40).unwrap();
     /// ```
-    #[rustfmt::skip]
-    pub fn read(&mut self, mut stream: impl Write, alloc_map: AllocMap) -> Result<()> {
-        let mut reader = Reader::new(self.tracks.clone(), alloc_map);
+    pub fn read(&mut self, stream: impl Write, track: u16, index: u64) -> Result<()> {
+        self.read_stream(stream, track, index, None)
+    }
 
-        // 无限循环
-        // 将轨道数据全部读取
-        // 写入外部流中
-    loop {
-        match reader.read()? {
-            Some(data) => stream.write_all(&data)?,
-            None => break
-        }
+    /// 容错读取
+    ///
+    /// 头部分片必须有效，
+    /// 后续分片无法读取时使用分片数据长度的0代替，
+    /// 分片头还可以解码时从下个分片继续读取，
+    /// 否则结束读取，
+    /// 适用于可以接受丢失部分数据的媒体文件
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::create("test.mp4").unwrap();
+    /// disk.read_lossy(file, 1, 40).unwrap();
+    /// ```
+    pub fn read_lossy(&mut self, stream: impl Write, track: u16, index: u64) -> Result<()> {
+        let size = self.options.chunk_size as usize - HEADER_SIZE;
+        self.read_stream(stream, track, index, Some(size))
     }
 
-        // 写入完成之后
-        // 清空尾部缓冲区，
-        // 将所有数据推入目的地
+    /// 读取数据并和副本比较
+    ///
+    /// 同时从当前存储和副本读取数据，
+    /// 内容不一致时返回`KernelError::ReplicaDiverged`，
+    /// 没有副本时和`read`相同
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::create("test.mp4").unwrap();
+    /// disk.read_verified(file, 1, 40).unwrap();
+    /// ```
+    pub fn read_verified(&mut self, mut stream: impl Write, track: u16, index: u64) -> Result<()> {
+        let replica = match self.replica.as_mut() {
+            Some(replica) => replica,
+            None => return self.read(stream, track, index),
+        };
+
+        let mut expected = Vec::new();
+        replica.read(&mut expected, track, index)?;
+        let mut data = Vec::new();
+        self.read(&mut data, track, index)?;
+        if data != expected {
+            return Err(KernelError::ReplicaDiverged.into());
+        }
+
+        stream.write_all(&data)?;
         stream.flush()?;
         Ok(())
     }
 
-    /// 打开写入流
+    /// 读取数据以及分片位置
+    ///
+    /// 按照链表顺序返回每个分片所在的轨道和位置以及分片数据，
+    /// 用于查看数据在轨道之间的分布
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// for chunk in disk.read_annotated(1, 40) {
+    ///     let (track, index, data) = chunk.unwrap();
+    /// }
+    /// ```
+    pub fn read_annotated(&mut self, track: u16, index: u64) -> impl Iterator<Item = Result<(u16, u64, Bytes)>> {
+        self.last_active = Instant::now();
+        let lease = Lease::new(self.readers.clone(), self.tracks.clone(), (track, index));
+        let mut reader = Reader::new(self.tracks.clone(), track, index);
+        std::iter::from_fn(move || {
+            // 迭代器存在期间持有租约
+            let _ = &lease;
+            match reader.read_annotated() {
+                Ok(chunk) => chunk.map(|(track, index, data)| Ok((track, index, Bytes::from(data)))),
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
+
+    /// 读取数据到环形缓冲区
+    ///
+    /// 逐个分片写入缓冲区，
+    /// 缓冲区满时阻塞直到消费方读取，
+    /// 读取结束之后关闭缓冲区，
+    /// 读取失败时标记缓冲区失败，
+    /// 消费方读完剩余数据之后得到错误
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions, RingBuffer};
+    /// use std::io::Read;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut ring = RingBuffer::new(4096 * 4);
+    /// let mut consumer = ring.clone();
+    /// let handle = std::thread::spawn(move || {
+    ///     let mut data = Vec::new();
+    ///     consumer.read_to_end(&mut data).unwrap();
+    ///     data
+    /// });
+    ///
+    /// disk.read_into_ring(1, 40, &mut ring).unwrap();
+    /// let data = handle.join().unwrap();
+    /// ```
+    pub fn read_into_ring(&mut self, track: u16, index: u64, ring: &mut RingBuffer) -> Result<()> {
+        let result = self.read(&mut *ring, track, index);
+        match &result {
+            Ok(_) => ring.close(),
+            Err(e) => ring.poison(e.to_string()),
+        }
+
+        result
+    }
+
+    /// 读取数据直到分隔符
+    ///
+    /// 沿着分片链表读取数据，
+    /// 遇到第一个分隔符之后停止，
+    /// 分隔符可以跨越分片边界，
+    /// 返回写入的字节数(包括分隔符)，
+    /// 没有找到分隔符时写入全部数据
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use super::{Disk, KernelOptions};
-    /// use std::fs::File;
     /// use std::rc::Rc;
     /// 
     /// let options = Rc::new(KernelOptions::from(
@@ -159,52 +423,66 @@ impl Disk {
     /// let mut disk = Disk::new(options);
     /// disk.init().unwrap();
     ///
-    /// let mut file = File::open("test.mp4");
-    /// let alloc_map = disk.write(file).unwrap();
+    /// let mut record = Vec::new();
+    /// let size = disk.read_until(1, 40, b"\r\n", &mut record).unwrap();
     /// ```
     #[rustfmt::skip]
-    pub fn write(&mut self, mut stream: impl Read) -> Result<AllocMap> {
-        let mut writer = Writer::new(self.tracks.clone(), self.options.clone());
-        let mut buffer = [0; 4096];
-        let mut size = 1;
+    pub fn read_until(&mut self, track: u16, index: u64, delim: &[u8], mut sink: impl Write) -> Result<u64> {
+        self.last_active = Instant::now();
+        self.check_head(track, index)?;
+        if self.options.access_stats {
+            self.touch(track, index)?;
+        }
 
-        // 无限循环
-        // 读取外部源写入轨道
-    loop {
-        
-        // 读取外部流数据
-        // 检查上次读取长度是否为空
-        // 如果不为空则不做重复调用
-        if size != 0 {
-            size = stream.read(&mut buffer)?;   
+        if delim.is_empty() {
+            return Ok(0);
         }
-        
-        // 检查数据为空的情况
-        let data = if size > 0 {
-            Some(&buffer[0..size]) 
-        } else { 
-            None
+
+        let mut reader = Reader::new(self.tracks.clone(), track, index);
+        let keep = delim.len() - 1;
+        let mut tail = Vec::with_capacity(keep * 2);
+        let mut size = 0;
+
+        // 上个分片末尾的数据已经写入，
+        // 这里只保留不足一个分隔符的部分，
+        // 和当前分片拼接之后查找跨越边界的分隔符
+    loop {
+        let data = match reader.read()? {
+            Some(data) => data,
+            None => break
         };
-        
-        // 向轨道写入数据
-        // 处理写入返回，如创建新轨道，
-        // 如果轨道返回头部索引，说明写入完成
-        if let Some(callback) = writer.write(data)? {
-            match callback {
-                Callback::CreateTrack(track) => self.create_track(track)?,
-                Callback::Done => return Ok(writer.alloc_map),
-                _ => ()
-            }
+
+        let skip = tail.len();
+        tail.extend_from_slice(&data);
+        if let Some(position) = tail.windows(delim.len()).position(|x| x == delim) {
+            let end = position + delim.len() - skip;
+            sink.write_all(&data[..end])?;
+            size += end as u64;
+            break;
         }
+
+        sink.write_all(&data)?;
+        size += data.len() as u64;
+        let start = tail.len().saturating_sub(keep);
+        tail.drain(..start);
     }
+
+        sink.flush()?;
+        Ok(size)
     }
 
-    /// 删除数据
+    /// 校验代数并打开读取流
+    ///
+    /// 外部引用的分片可能已经被删除并且被其他数据复用，
+    /// 这里先检查头部分片的标记和代数，
+    /// 数据已经删除时返回`KernelError::NotFound`，
+    /// 代数不一致时返回`KernelError::StaleHandle`
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
     /// use std::rc::Rc;
     /// 
     /// let options = Rc::new(KernelOptions::from(
@@ -215,31 +493,3042 @@ impl Disk {
     /// let mut disk = Disk::new(options);
     /// disk.init().unwrap();
     ///
-    /// disk.remove(0, 16).unwrap();
+    /// let (track, index) = disk.write(File::open("test.mp4").unwrap()).unwrap();
+    /// let generation = disk.generation(track, index).unwrap();
+    ///
+    /// let mut file = File::create("output.mp4").unwrap();
+    /// disk.read_checked(file, track, index, generation).unwrap();
     /// ```
-    #[rustfmt::skip]
-    pub fn remove(&mut self, alloc_map: &AllocMap) -> Result<()> {
-        let mut tracks = self.tracks.borrow_mut();
-        for (track_id, list) in alloc_map {
-            if let Some(track) = tracks.get_mut(track_id) {
-                track.remove(list)?;
-            }
+    pub fn read_checked(&mut self, stream: impl Write, track: u16, index: u64, generation: u16) -> Result<()> {
+        if self.generation(track, index)? != generation {
+            return Err(KernelError::StaleHandle.into());
         }
 
-        Ok(())
+        self.read(stream, track, index)
     }
 
-    /// 创建轨道
+    /// 获取头部分片代数
     ///
-    /// 创建轨道类并初始化，
-    /// 将轨道添加到内部的轨道列表
-    #[rustfmt::skip]
-    fn create_track(&mut self, id: u16) -> Result<()> {
-        let mut track = Track::new(id, self.options.clone())?;
-        track.init()?;
-        self.tracks
-            .borrow_mut()
-            .insert(id, track);
-        Ok(())
+    /// 如果给定位置不是头部分片，
+    /// 返回`KernelError::NotFound`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let generation = disk.generation(1, 40).unwrap();
+    /// ```
+    pub fn generation(&mut self, track: u16, index: u64) -> Result<u16> {
+        Ok(self.check_head(track, index)?.generation)
+    }
+
+    /// 获取数据读取统计
+    ///
+    /// 需要开启`KernelOptions::access_stats`，
+    /// 统计保存在头部分片中，
+    /// 如果给定位置不是头部分片，
+    /// 返回`KernelError::NotFound`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let mut options = KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// options.access_stats = true;
+    /// let mut disk = Disk::new(Rc::new(options));
+    /// disk.init().unwrap();
+    ///
+    /// let stats = disk.object_stats(1, 40).unwrap();
+    /// ```
+    pub fn object_stats(&mut self, track: u16, index: u64) -> Result<ObjectStats> {
+        let header = self.check_head(track, index)?;
+        Ok(ObjectStats { hits: header.hits, accessed: header.accessed })
+    }
+
+    /// 打开读取流
+    ///
+    /// 检查头部分片之后返回按照分片读取的数据流，
+    /// 数据流每次只保留一个分片的数据，
+    /// 读取失败时返回包装了`KernelError`的IO错误，
+    /// 数据流存在期间`swap`不会删除这个链表
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::create("test.mp4").unwrap();
+    /// let mut stream = disk.open_reader(1, 40).unwrap();
+    /// std::io::copy(&mut stream, &mut file).unwrap();
+    /// ```
+    pub fn open_reader(&mut self, track: u16, index: u64) -> Result<ReadStream> {
+        self.check_head(track, index)?;
+        self.last_active = Instant::now();
+        if self.options.access_stats {
+            self.touch(track, index)?;
+        }
+
+        let lease = Lease::new(self.readers.clone(), self.tracks.clone(), (track, index));
+        let reader = Reader::new(self.tracks.clone(), track, index);
+        Ok(ReadStream::new(reader, lease))
+    }
+
+    /// 打开写入流
+    ///
+    /// 写入完成之后返回头部分片所在的轨道和位置
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::open("test.mp4").unwrap();
+    /// let (track, index) = disk.write(file).unwrap();
+    /// ```
+    pub fn write(&mut self, stream: impl Read) -> Result<(u16, u64)> {
+        Ok(self.write_teed(stream, |_| Ok(()))?.head.unwrap())
+    }
+
+    /// 按照预计分片数量写入数据
+    ///
+    /// 写入之前在同一个轨道中预先分配`expected_chunks`个分片，
+    /// 让数据的分片尽量连续，
+    /// 实际数据更长时继续分配，更短时释放多余的分片
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::open("test.mp4").unwrap();
+    /// let (track, index) = disk.write_hinted(file, 1024).unwrap();
+    /// ```
+    pub fn write_hinted(&mut self, stream: impl Read, expected_chunks: usize) -> Result<(u16, u64)> {
+        let writer = self.write_teed(stream, |writer| writer.reserve(expected_chunks))?;
+        Ok(writer.head.unwrap())
+    }
+
+    /// 批量写入数据
+    ///
+    /// 每个数据需要给出长度，
+    /// 写入之前一次性为所有数据分配分片并按照位置排序，
+    /// 然后按照顺序依次填充，
+    /// 让整批数据尽量占据连续的区域，
+    /// 实际长度和给出的长度不同时按照`write_hinted`处理，
+    /// 禁止跨轨道时逐个预分配.
+    ///
+    /// 任何数据写入失败时删除这一批已经写入的数据，
+    /// 返回每个数据的头部分片，顺序和输入相同
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let heads = disk.write_batch(vec![
+    ///     (5, &b"hello"[..]),
+    ///     (5, &b"world"[..]),
+    /// ]).unwrap();
+    /// ```
+    pub fn write_batch(&mut self, objects: Vec<(u64, impl Read)>) -> Result<Vec<(u16, u64)>> {
+        if self.replica.is_none() {
+            let mut objects = objects;
+            return self.write_batch_local(objects
+                .iter_mut()
+                .map(|(size, stream)| (*size, stream as &mut dyn Read))
+                .collect());
+        }
+
+        let mut buffers = Vec::with_capacity(objects.len());
+        for (size, mut stream) in objects {
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data)?;
+            buffers.push((size, data));
+        }
+
+        let mut slices = buffers.iter().map(|(size, data)| (*size, &data[..])).collect::<Vec<_>>();
+        let heads = self.write_batch_local(slices
+            .iter_mut()
+            .map(|(size, data)| (*size, data as &mut dyn Read))
+            .collect())?;
+
+        // 副本写入失败或者结果不一致时
+        // 删除当前存储中已经写入的这一批数据
+        let mut slices = buffers.iter().map(|(size, data)| (*size, &data[..])).collect::<Vec<_>>();
+        let replica = self.replica.as_mut().unwrap();
+        let result = replica.write_batch_local(slices
+            .iter_mut()
+            .map(|(size, data)| (*size, data as &mut dyn Read))
+            .collect());
+        let error = match result {
+            Ok(mirrored) if mirrored == heads => return Ok(heads),
+            Ok(mirrored) => {
+                for (track, index) in mirrored {
+                    replica.discard_object(track, index)?;
+                }
+
+                KernelError::ReplicaDiverged.into()
+            },
+            Err(e) => e,
+        };
+
+        for (track, index) in heads {
+            self.discard_object(track, index)?;
+        }
+
+        Err(error)
+    }
+
+    /// 分段并行写入数据
+    ///
+    /// 将数据平均分为`segments`段，
+    /// 每段依次分配在不同的轨道，
+    /// 分段之间通过跨轨道链接按照顺序连接，
+    /// 然后每个轨道使用独立的文件句柄并行写入，
+    /// 读取时和普通数据一样沿着链表读取.
+    ///
+    /// 写入之前需要将数据全部读入内存，
+    /// 禁止跨轨道时和`write`相同
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::open("test.mp4").unwrap();
+    /// let (track, index) = disk.write_split(file, 4).unwrap();
+    /// ```
+    pub fn write_split(&mut self, stream: impl Read, segments: usize) -> Result<(u16, u64)> {
+        self.replicated(stream, |disk, stream| {
+            if !disk.options.spill {
+                let writer = Writer::new(disk.tracks.clone(), disk.options.clone());
+                return Ok(disk.write_stream(stream, writer)?.head.unwrap());
+            }
+
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data)?;
+            if let Some(limit) = disk.options.max_object_size {
+                if data.len() as u64 > limit {
+                    return Err(KernelError::ObjectTooLarge(limit).into());
+                }
+            }
+
+            disk.write_split_local(&data, segments.max(1))
+        })
+    }
+
+    /// 打开写入流并返回分片分布
+    ///
+    /// 除了头部分片之外，
+    /// 同时按照链表顺序返回数据所有分片所在的轨道和位置，
+    /// 方便外部建立自己的索引
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::open("test.mp4").unwrap();
+    /// let (head, layout) = disk.write_with_layout(file).unwrap();
+    /// ```
+    pub fn write_with_layout(&mut self, stream: impl Read) -> Result<(Head, Vec<Head>)> {
+        let writer = self.write_teed(stream, |writer| {
+            writer.layout = Some(Vec::new());
+            Ok(())
+        })?;
+
+        Ok((writer.head.unwrap(), writer.layout.unwrap()))
+    }
+
+    /// 获取分片分布
+    ///
+    /// 从头部分片开始沿着链表只读取分片头，
+    /// 按照链表顺序返回所有分片所在的轨道和位置，
+    /// 链表指向不存在的轨道时返回`KernelError::MissingTrack`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let layout = disk.object_layout(1, 40).unwrap();
+    /// ```
+    pub fn object_layout(&mut self, track: u16, index: u64) -> Result<Vec<(u16, u64)>> {
+        let mut tracks = self.tracks.borrow_mut();
+        let mut next = Some((track, index));
+        let mut layout: Vec<(u16, u64)> = Vec::new();
+        while let Some((track_id, index)) = next {
+            next = match (tracks.get_mut(&track_id), layout.last().copied()) {
+                (Some(track), _) => track.header(index)?.next,
+                (None, None) => return Err(KernelError::MissingTrack(track_id).into()),
+                (None, Some((source, offset))) => return Err(KernelError::MissingTrack(track_id))
+                    .with_context(|| format!("linked from track {} offset {}", source, offset)),
+            };
+
+            layout.push((track_id, index));
+        }
+
+        Ok(layout)
+    }
+
+    /// 预留数据ID
+    ///
+    /// 在数据写入之前分配稳定的ID，
+    /// 之后通过`write_with_id`写入数据，
+    /// 未写入的ID可以通过`release_id`回收，
+    /// 重新打开之后也会被自动回收
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// ```
+    pub fn reserve_id(&mut self) -> Result<ObjectId> {
+        if let Some(replica) = self.replica.as_mut() {
+            replica.reserve_id()?;
+        }
+
+        Ok(self.objects()?.reserve())
+    }
+
+    /// 回收预留的数据ID
+    ///
+    /// 只能回收还未写入的ID，
+    /// 返回是否回收成功
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// assert!(disk.release_id(id).unwrap());
+    /// ```
+    pub fn release_id(&mut self, id: ObjectId) -> Result<bool> {
+        if let Some(replica) = self.replica.as_mut() {
+            replica.release_id(id)?;
+        }
+
+        Ok(self.objects()?.release(id))
+    }
+
+    /// 写入预留ID的数据
+    ///
+    /// 数据写入完成之后才更新ID表，
+    /// ID没有预留时返回`KernelError::InvalidId`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// disk.write_with_id(id, File::open("test.mp4").unwrap()).unwrap();
+    /// ```
+    pub fn write_with_id(&mut self, id: ObjectId, stream: impl Read) -> Result<()> {
+        if !self.objects()?.is_reserved(id) {
+            return Err(KernelError::InvalidId(id.0).into());
+        }
+
+        let head = self.write(stream)?;
+        if let Some(replica) = self.replica.as_mut() {
+            replica.objects()?.commit(id, head)?;
+        }
+
+        self.objects()?.commit(id, head)
+    }
+
+    /// 获取数据ID对应的头部分片
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// assert_eq!(disk.object_head(id).unwrap(), None);
+    /// ```
+    pub fn object_head(&mut self, id: ObjectId) -> Result<Option<Head>> {
+        Ok(self.objects()?.get(id))
+    }
+
+    /// 通过数据ID读取数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// disk.write_with_id(id, File::open("test.mp4").unwrap()).unwrap();
+    /// disk.read_id(File::create("output.mp4").unwrap(), id).unwrap();
+    /// ```
+    pub fn read_id(&mut self, stream: impl Write, id: ObjectId) -> Result<()> {
+        match self.objects()?.get(id) {
+            Some((track, index)) => self.read(stream, track, index),
+            None => Err(KernelError::InvalidId(id.0).into()),
+        }
+    }
+
+    /// 替换数据ID的内容
+    ///
+    /// 先将新的内容写入新的分片链表，
+    /// 然后将数据ID指向新的头部分片，
+    /// 最后删除旧的分片链表，
+    /// 因为更新数据ID只写入单个表项，
+    /// 所以通过数据ID读取时只会看到完整的旧内容或者新内容，
+    /// 写入失败时数据ID仍然指向旧内容.
+    /// 旧链表上还有`read_annotated`打开的读取流时，
+    /// 配额和副本立即更新，
+    /// 当前存储的旧链表推迟到最后一个读取流释放时删除
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// disk.write_with_id(id, File::open("old.mp4").unwrap()).unwrap();
+    /// disk.swap(id, File::open("new.mp4").unwrap()).unwrap();
+    /// ```
+    pub fn swap(&mut self, id: ObjectId, stream: impl Read) -> Result<()> {
+        let (track, index) = match self.objects()?.get(id) {
+            Some(head) => head,
+            None => return Err(KernelError::InvalidId(id.0).into()),
+        };
+
+        let head = self.write(stream)?;
+        if let Some(replica) = self.replica.as_mut() {
+            replica.objects()?.commit(id, head)?;
+        }
+
+        self.objects()?.commit(id, head)?;
+        if !self.readers.borrow_mut().defer((track, index)) {
+            return self.remove(track, index);
+        }
+
+        self.uncharge(track, index)?;
+        if let Some(replica) = self.replica.as_mut() {
+            replica.remove(track, index)?;
+        }
+
+        Ok(())
+    }
+
+    /// 通过数据ID删除数据
+    ///
+    /// 删除数据之后回收ID
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let id = disk.reserve_id().unwrap();
+    /// disk.remove_id(id).unwrap();
+    /// ```
+    pub fn remove_id(&mut self, id: ObjectId) -> Result<()> {
+        if let Some(replica) = self.replica.as_mut() {
+            replica.objects()?.remove(id)?;
+        }
+
+        match self.objects()?.remove(id)? {
+            Some((track, index)) => self.remove(track, index),
+            None => Err(KernelError::InvalidId(id.0).into()),
+        }
+    }
+
+    /// 删除数据
+    ///
+    /// 从给定的头部分片开始，
+    /// 沿着分片链表逐个轨道删除
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// disk.remove(1, 40).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn remove(&mut self, track: u16, index: u64) -> Result<()> {
+        self.check_head(track, index)?;
+        self.uncharge(track, index)?;
+        self.remove_local(track, index)?;
+        if let Some(replica) = self.replica.as_mut() {
+            replica.remove(track, index)?;
+        }
+
+        Ok(())
+    }
+
+    /// 设置存储配额
+    ///
+    /// 使用量从已有数据的长度开始统计，
+    /// 之后按照写入和删除的数据长度更新，
+    /// 副本不受配额限制.
+    ///
+    /// 因为写入之前无法知道数据长度，
+    /// 所以在数据写入之后检查配额，
+    /// 超出配额时调用`on_quota_exceeded`，
+    /// 回调参数为写入之后的使用量和配额，
+    /// 回调返回`QuotaDecision::Reject`时删除刚写入的数据
+    /// 并返回`KernelError::QuotaExceeded`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions, QuotaDecision};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// disk.set_quota(1024 * 1024, |used, limit| {
+    ///     println!("quota exceeded: {} / {}", used, limit);
+    ///     QuotaDecision::Reject
+    /// }).unwrap();
+    /// ```
+    pub fn set_quota(
+        &mut self, 
+        limit: u64, 
+        on_quota_exceeded: impl FnMut(u64, u64) -> QuotaDecision + 'static
+    ) -> Result<()> {
+        let mut used = 0;
+        for (track, index) in self.heads()? {
+            used += self.object_size(track, index)?;
+        }
+
+        self.quota = Some(Quota::new(limit, used, on_quota_exceeded));
+        Ok(())
+    }
+
+    /// 获取配额使用情况
+    ///
+    /// 返回已经使用的字节数和配额，
+    /// 没有设置配额时返回空
+    pub fn quota_usage(&self) -> Option<(u64, u64)> {
+        self.quota.as_ref().map(|quota| (quota.used, quota.limit))
+    }
+
+    /// 取消存储配额
+    pub fn clear_quota(&mut self) {
+        self.quota = None;
+    }
+
+    /// 删除本地数据
+    ///
+    /// 不会在副本上重放，
+    /// 链表指向不存在的轨道时返回`KernelError::MissingTrack`，
+    /// 之前轨道中的分片已经被删除
+    fn remove_local(&mut self, track: u16, index: u64) -> Result<()> {
+        self.last_active = Instant::now();
+        remove_chain(&mut self.tracks.borrow_mut(), track, index)
+    }
+
+    /// 获取轨道的分片填充比例
+    ///
+    /// 参考`Track::padding_waste`，
+    /// 轨道不存在时返回`KernelError::MissingTrack`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let waste = disk.padding_waste(1).unwrap();
+    /// ```
+    pub fn padding_waste(&mut self, track: u16) -> Result<f64> {
+        match self.tracks.borrow_mut().get_mut(&track) {
+            Some(track) => track.padding_waste(),
+            None => Err(KernelError::MissingTrack(track).into()),
+        }
+    }
+
+    /// 获取轨道连续失效分片的分布
+    ///
+    /// 参考`Track::free_run_histogram`，
+    /// 轨道不存在时返回`KernelError::MissingTrack`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let histogram = disk.free_run_histogram(1).unwrap();
+    /// ```
+    pub fn free_run_histogram(&mut self, track: u16) -> Result<Vec<(u64, u64)>> {
+        match self.tracks.borrow_mut().get_mut(&track) {
+            Some(track) => track.free_run_histogram(),
+            None => Err(KernelError::MissingTrack(track).into()),
+        }
+    }
+
+    /// 是否有轨道的位图还在重建
+    ///
+    /// 初始化超出`bitmap_rebuild_budget`时为`true`，
+    /// 各个轨道在第一次需要位图时完成重建
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let rebuilding = disk.is_rebuilding();
+    /// ```
+    pub fn is_rebuilding(&self) -> bool {
+        self.tracks.borrow().values().any(|x| x.is_rebuilding())
+    }
+
+    /// 获取所有头部分片
+    ///
+    /// 按照轨道和位置顺序返回所有数据的头部分片
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let heads = disk.heads().unwrap();
+    /// ```
+    pub fn heads(&mut self) -> Result<Vec<(u16, u64)>> {
+        let mut tracks = self.tracks.borrow_mut();
+        let mut ids = tracks.keys().copied().collect::<Vec<u16>>();
+        let mut heads = Vec::new();
+        ids.sort_unstable();
+        for id in ids {
+            for index in tracks.get_mut(&id).unwrap().heads()? {
+                heads.push((id, index));
+            }
+        }
+
+        Ok(heads)
+    }
+
+    /// 获取数据长度
+    ///
+    /// 从头部分片开始沿着链表只读取分片头，
+    /// 累加所有分片的数据长度
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let size = disk.object_size(1, 40).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn object_size(&mut self, track: u16, index: u64) -> Result<u64> {
+        self.check_head(track, index)?;
+        let mut tracks = self.tracks.borrow_mut();
+        let mut next = Some((track, index));
+        let mut source = None;
+        let mut size = 0;
+        while let Some((track_id, index)) = next {
+            let header = match (tracks.get_mut(&track_id), source) {
+                (Some(track), _) => track.header(index)?,
+                (None, None) => return Err(KernelError::MissingTrack(track_id).into()),
+                (None, Some((source, offset))) => return Err(KernelError::MissingTrack(track_id))
+                    .with_context(|| format!("linked from track {} offset {}", source, offset)),
+            };
+
+            source = Some((track_id, index));
+            size += header.size as u64;
+            next = header.next;
+        }
+
+        Ok(size)
+    }
+
+    /// 获取最大的数据
+    ///
+    /// 遍历所有头部分片并计算数据长度，
+    /// 按照长度从大到小返回最大的`n`个数据，
+    /// 内部只保留`n`个候选，内存占用有上限
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let largest = disk.largest_objects(10).unwrap();
+    /// ```
+    pub fn largest_objects(&mut self, n: usize) -> Result<Vec<((u16, u64), u64)>> {
+        let mut ids = self.tracks.borrow().keys().copied().collect::<Vec<u16>>();
+        let mut heap = BinaryHeap::with_capacity(n + 1);
+        ids.sort_unstable();
+
+        // 逐个轨道遍历头部分片，
+        // 不会同时保存所有轨道的头部分片
+        for id in ids {
+            let heads = self.tracks.borrow_mut().get_mut(&id).unwrap().heads()?;
+            for index in heads {
+                heap.push(Reverse((self.object_size(id, index)?, (id, index))));
+                if heap.len() > n {
+                    heap.pop();
+                }
+            }
+        }
+
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, head))| (head, size))
+            .collect())
+    }
+
+    /// 导出所有数据
+    ///
+    /// 将所有数据依次写入归档，
+    /// 每个数据由头部分片所在的轨道和位置开始，
+    /// 之后是若干个分段，每个分段为`U32`长度和数据，
+    /// 最后以长度为0的分段结束
+    ///
+    /// ```
+    ///     +-----+-----+-----+----------+-----+----------+-----+
+    ///     | U16 | U64 | U32 | * (data) | U32 | * (data) | 0   |
+    ///     +-----+-----+-----+----------+-----+----------+-----+
+    /// ```
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::create("backup.archive").unwrap();
+    /// disk.export(file).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn export(&mut self, mut stream: impl Write) -> Result<()> {
+        for (track, index) in self.heads()? {
+            stream.write_all(&track.to_be_bytes())?;
+            stream.write_all(&index.to_be_bytes())?;
+
+            // 每个分片的数据作为一个分段，
+            // 空分段会被当作结束标记，所以跳过
+            let mut reader = Reader::new(self.tracks.clone(), track, index);
+            while let Some(data) = reader.read()? {
+                if !data.is_empty() {
+                    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+                    stream.write_all(&data)?;
+                }
+            }
+
+            stream.write_all(&0u32.to_be_bytes())?;
+        }
+
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// 导入数据
+    ///
+    /// 从归档中流式读取每个数据并写入，
+    /// 数据按照当前的分片大小重新分片，
+    /// 所以归档来源的分片大小可以和当前不同，
+    /// 返回每个数据在源中的头部分片和新的头部分片
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::fs::File;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut file = File::open("backup.archive").unwrap();
+    /// let heads = disk.import(file).unwrap();
+    /// ```
+    pub fn import(&mut self, mut stream: impl Read) -> Result<Vec<(Head, Head)>> {
+        let mut heads = Vec::new();
+        while let Some(source) = archive::read_head(&mut stream)? {
+            let head = self.write(Segments::new(&mut stream))?;
+            heads.push((source, head));
+        }
+
+        Ok(heads)
+    }
+
+    /// 压缩轨道
+    ///
+    /// 将轨道尾部的有效分片迁移到靠前的失效分片，
+    /// 然后截断轨道尾部释放空间，
+    /// `pinned`中的头部分片保持原位不动，
+    /// 其他分片（包括固定数据的后续分片）都可以迁移，
+    /// 需要保持所有数据位置时将全部头部分片传入即可，
+    /// 返回被迁移的头部分片的原位置和新位置
+    ///
+    /// 迁移过程中定期调用`on_progress(已迁移分片数, 需要迁移的分片总数)`，
+    /// 迁移完成时总是以总数调用一次
+    ///
+    /// 因为分片的前驱可能位于其他轨道，
+    /// 所以这里需要读取所有轨道的有效分片建立前驱索引
+    ///
+    /// 设置副本时先比较两边的迁移计划，
+    /// 计划不一致时不迁移任何分片并返回`KernelError::ReplicaDiverged`，
+    /// 迁移过程中的IO错误不会回滚已经迁移的分片
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::collections::HashSet;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let mut pinned = HashSet::new();
+    /// pinned.insert((1, 40));
+    ///
+    /// let moved = disk.compact(1, &pinned, |compacted, total| {
+    ///     println!("{}/{}", compacted, total);
+    /// }).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn compact(
+        &mut self, 
+        id: u16, 
+        pinned: &HashSet<Head>, 
+        mut on_progress: impl FnMut(u64, u64)
+    ) -> Result<Moves> {
+        let plan = match self.compact_plan(id, pinned)? {
+            Some(plan) => plan,
+            None => return Ok(Vec::new()),
+        };
+
+        // 副本按照相同的规则生成迁移计划，
+        // 两边一致时才开始迁移
+        if let Some(replica) = self.replica.as_mut() {
+            let mirror = replica.compact_plan(id, pinned)?;
+            if mirror.map(|x| x.moves).as_ref() != Some(&plan.moves) {
+                return Err(KernelError::ReplicaDiverged.into());
+            }
+        }
+
+        let moved = self.apply_compact(id, plan, &mut on_progress)?;
+
+        // 被迁移的数据ID指向新的位置
+        self.objects()?.remap(&moved)?;
+        if let Some(replica) = self.replica.as_mut() {
+            let ignore: &mut dyn FnMut(u64, u64) = &mut |_, _| {};
+            if replica.compact(id, pinned, ignore)? != moved {
+                return Err(KernelError::ReplicaDiverged.into());
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// 生成轨道压缩计划
+    ///
+    /// 从尾部开始匹配有效分片和失效分片，
+    /// 轨道不存在时返回`None`
+    fn compact_plan(&mut self, id: u16, pinned: &HashSet<Head>) -> Result<Option<CompactPlan>> {
+        let mut tracks = self.tracks.borrow_mut();
+        if !tracks.contains_key(&id) {
+            return Ok(None);
+        }
+
+        let mut preds = HashMap::new();
+        let mut holes = Vec::new();
+        let mut movable = Vec::new();
+
+        // 遍历所有轨道的有效分片，
+        // 记录指向目标轨道的前驱分片，
+        // 以及目标轨道中可以迁移的分片
+        for (track_id, track) in tracks.iter_mut() {
+            let free = track.free_list()?;
+            let offsets = track.offsets().collect::<Vec<u64>>();
+            let free_set = free.iter().copied().collect::<HashSet<u64>>();
+            for offset in offsets {
+                if free_set.contains(&offset) {
+                    continue;
+                }
+
+                let header = track.header(offset)?;
+                if let Some((next_track, next)) = header.next {
+                    if next_track == id {
+                        preds.insert(next, (*track_id, offset));
+                    }
+                }
+
+                if *track_id == id && !(header.is_head() && pinned.contains(&(id, offset))) {
+                    movable.push((offset, header.is_head()));
+                }
+            }
+
+            if *track_id == id {
+                holes = free;
+            }
+        }
+
+        holes.sort_unstable();
+        movable.sort_unstable_by(|a, b| b.cmp(a));
+
+        // 从尾部开始匹配有效分片和失效分片，
+        // 直到没有比当前分片更靠前的失效分片
+        let mut moves = Vec::new();
+        let mut holes = holes.into_iter().peekable();
+        for (src, head) in movable {
+            match holes.peek() {
+                Some(dst) if *dst < src => moves.push((src, holes.next().unwrap(), head)),
+                _ => break,
+            }
+        }
+
+        let holes = holes.collect();
+        Ok(Some(CompactPlan { preds, moves, holes }))
+    }
+
+    /// 按照压缩计划迁移分片
+    ///
+    /// 迁移完成之后释放源位置和剩余的失效分片，
+    /// 返回被迁移的头部分片的原位置和新位置
+    fn apply_compact(&mut self, id: u16, plan: CompactPlan, on_progress: &mut impl FnMut(u64, u64)) -> Result<Moves> {
+        let mut tracks = self.tracks.borrow_mut();
+        let CompactPlan { mut preds, moves, holes } = plan;
+        let mut moved = Vec::new();
+        let mut released = Vec::new();
+        let total = moves.len() as u64;
+        for (i, (src, dst, head)) in moves.into_iter().enumerate() {
+            if i % PROGRESS_INTERVAL == 0 {
+                on_progress(i as u64, total);
+            }
+
+            // 迁移分片并更新前驱分片的链接，
+            // 后续分片的前驱也随之改变
+            let next = tracks.get_mut(&id).unwrap().relocate(src, dst)?;
+            if let Some((track_id, offset)) = preds.remove(&src) {
+                tracks.get_mut(&track_id).unwrap().link(offset, (id, dst))?;
+            }
+
+            if let Some((next_track, next)) = next {
+                if next_track == id {
+                    preds.insert(next, (id, dst));
+                }
+            }
+
+            if head {
+                moved.push(((id, src), (id, dst)));
+            }
+
+            released.push(src);
+        }
+
+        on_progress(total, total);
+        released.extend(holes);
+        tracks.get_mut(&id).unwrap().release(released)?;
+        Ok(moved)
+    }
+
+    /// 获取需要压缩的轨道
+    ///
+    /// 失效分片比例达到`free_ratio`，
+    /// 或者失效分片数量达到`free_count`的轨道需要压缩，
+    /// 大量零散的失效分片本身就是压缩的理由，
+    /// 没有配置`KernelOptions::compact_trigger`时返回空
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions, CompactTrigger};
+    /// use std::rc::Rc;
+    /// 
+    /// let mut options = KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// options.compact_trigger = Some(CompactTrigger {
+    ///     free_ratio: 0.5,
+    ///     free_count: 1024,
+    /// });
+    ///
+    /// let mut disk = Disk::new(Rc::new(options));
+    /// disk.init().unwrap();
+    ///
+    /// let ids = disk.compaction_candidates().unwrap();
+    /// ```
+    pub fn compaction_candidates(&mut self) -> Result<Vec<u16>> {
+        let trigger = match &self.options.compact_trigger {
+            Some(trigger) => trigger,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut ids = Vec::new();
+        for (id, track) in self.tracks.borrow_mut().iter_mut() {
+            let total = track.offsets().count() as u64;
+            let free_count = track.free_list()?.len() as u64;
+            if free_count == 0 {
+                continue;
+            }
+
+            let free_ratio = free_count as f64 / total as f64;
+            if free_ratio >= trigger.free_ratio || free_count >= trigger.free_count {
+                ids.push(*id);
+            }
+        }
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// 按需压缩轨道
+    ///
+    /// 压缩所有需要压缩的轨道，
+    /// 参数和返回值参考`compact`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions, CompactTrigger};
+    /// use std::collections::HashSet;
+    /// use std::rc::Rc;
+    /// 
+    /// let mut options = KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// options.compact_trigger = Some(CompactTrigger {
+    ///     free_ratio: 0.5,
+    ///     free_count: 1024,
+    /// });
+    ///
+    /// let mut disk = Disk::new(Rc::new(options));
+    /// disk.init().unwrap();
+    ///
+    /// let moved = disk.maybe_compact(&HashSet::new()).unwrap();
+    /// ```
+    pub fn maybe_compact(&mut self, pinned: &HashSet<Head>) -> Result<Moves> {
+        let mut moved = Vec::new();
+        for id in self.compaction_candidates()? {
+            moved.extend(self.compact(id, pinned, |_, _| {})?);
+        }
+
+        Ok(moved)
+    }
+
+    /// 保存状态
+    ///
+    /// 保存所有轨道未保存的轨道头，
+    /// 延迟保存失效链表时需要定期调用
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// disk.flush().unwrap();
+    /// ```
+    pub fn flush(&mut self) -> Result<()> {
+        for track in self.tracks.borrow_mut().values_mut() {
+            if track.is_dirty() {
+                track.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 校验数据
+    ///
+    /// 检查所有有效分片的长度和下个分片的位置，
+    /// 读取速度受`KernelOptions::verify_rate`限制，
+    /// 返回发现的损坏分片
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let report = disk.verify().unwrap();
+    /// assert!(report.errors.is_empty());
+    /// ```
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let sizes = self.track_sizes()?;
+        let options = self.options.clone();
+        let limiter = Limiter::new(options.verify_rate);
+        let path: &Path = options.path.as_ref();
+        let mut report = VerifyReport::default();
+        for task in self.verify_tasks(&sizes)? {
+            report.merge(task.run(path, &limiter)?);
+        }
+
+        Ok(report)
+    }
+
+    /// 并行校验数据
+    ///
+    /// 最多使用`parallelism`个线程同时校验不同的轨道，
+    /// 所有线程共享`KernelOptions::verify_rate`限速，
+    /// 合并之后的报告和`verify`一致
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions};
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut disk = Disk::new(options);
+    /// disk.init().unwrap();
+    ///
+    /// let report = disk.verify_parallel(4).unwrap();
+    /// assert!(report.errors.is_empty());
+    /// ```
+    #[rustfmt::skip]
+    pub fn verify_parallel(&mut self, parallelism: usize) -> Result<VerifyReport> {
+        let sizes = self.track_sizes()?;
+        let options = self.options.clone();
+        let limiter = Limiter::new(options.verify_rate);
+        let path: &Path = options.path.as_ref();
+        let tasks = Mutex::new(self.verify_tasks(&sizes)?);
+
+        // 每个线程从任务队列中领取轨道，
+        // 直到所有轨道校验完成，
+        // 线程崩溃时作为错误返回
+        let reports = std::thread::scope(|scope| {
+            let workers = (0..parallelism.max(1)).map(|_| scope.spawn(|| {
+                let mut report = VerifyReport::default();
+            loop {
+                let task = tasks.lock().unwrap().pop();
+                match task {
+                    Some(task) => report.merge(task.run(path, &limiter)?),
+                    None => break Ok::<_, anyhow::Error>(report),
+                }
+            }
+            })).collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|_| Err(anyhow!("verify worker panicked"))))
+                .collect::<Vec<_>>()
+        });
+
+        let mut report = VerifyReport::default();
+        for item in reports {
+            report.merge(item?);
+        }
+
+        Ok(report)
+    }
+
+    /// 空闲维护
+    ///
+    /// 如果配置了空闲整理，
+    /// 并且距离上次读写删除已经超过空闲时间，
+    /// 则在IO预算内依次整理各个轨道的失效链表，
+    /// 整理不会移动有效数据，
+    /// 需要由外部在空闲时定期调用，
+    /// 返回是否执行了整理
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Disk, KernelOptions, IdleDefrag};
+    /// use std::time::Duration;
+    /// use std::rc::Rc;
+    /// 
+    /// let mut options = KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// options.idle_defrag = Some(IdleDefrag {
+    ///     idle: Duration::from_secs(10),
+    ///     io_budget: 4096,
+    /// });
+    ///
+    /// let mut disk = Disk::new(Rc::new(options));
+    /// disk.init().unwrap();
+    ///
+    /// disk.maintain().unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn maintain(&mut self) -> Result<bool> {
+        let defrag = match &self.options.idle_defrag {
+            Some(defrag) => defrag,
+            None => return Ok(false),
+        };
+
+        if self.last_active.elapsed() < defrag.idle {
+            return Ok(false);
+        }
+
+        let budget = defrag.io_budget;
+        self.tidy(budget)?;
+        Ok(true)
+    }
+
+    /// 整理所有轨道
+    ///
+    /// 按照轨道ID顺序整理，
+    /// 预算耗尽之后剩余轨道留到下次
+    fn tidy(&mut self, mut budget: u64) -> Result<()> {
+        if let Some(replica) = self.replica.as_mut() {
+            replica.tidy(budget)?;
+        }
+
+        let mut tracks = self.tracks.borrow_mut();
+        let mut ids = tracks.keys().copied().collect::<Vec<u16>>();
+        ids.sort_unstable();
+        for id in ids {
+            if !tracks.get_mut(&id).unwrap().tidy(&mut budget)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 重放写入
+    ///
+    /// 没有副本时直接写入，
+    /// 否则先将数据读入内存，
+    /// 然后分别写入当前存储和副本并比较头部分片，
+    /// 只给需要完整数据的写入使用.
+    ///
+    /// 副本写入失败或者结果不一致时
+    /// 删除当前存储中已经写入的数据
+    fn replicated(
+        &mut self, 
+        mut stream: impl Read, 
+        write: impl Fn(&mut Disk, &mut dyn Read) -> Result<(u16, u64)>
+    ) -> Result<(u16, u64)> {
+        if self.replica.is_none() {
+            return write(self, &mut stream);
+        }
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data)?;
+        let head = write(self, &mut &data[..])?;
+        let replica = self.replica.as_mut().unwrap();
+        let error = match write(replica, &mut &data[..]) {
+            Ok(mirrored) if mirrored == head => return Ok(head),
+            Ok(mirrored) => {
+                replica.discard_object(mirrored.0, mirrored.1)?;
+                KernelError::ReplicaDiverged.into()
+            },
+            Err(e) => e,
+        };
+
+        self.discard_object(head.0, head.1)?;
+        Err(error)
+    }
+
+    /// 同时写入当前存储和副本
+    ///
+    /// 没有副本时直接写入，
+    /// 否则每次从外部流读取一块数据，
+    /// 依次交给当前存储和副本的写入流，
+    /// 不需要将数据全部读入内存.
+    ///
+    /// `configure`分别用于配置两边的写入流，
+    /// 任何一方写入失败或者分片分布不一致时
+    /// 删除两边已经写入的数据
+    fn write_teed(&mut self, stream: impl Read, configure: impl Fn(&mut Writer) -> Result<()>) -> Result<Writer> {
+        let mut writer = Writer::new(self.tracks.clone(), self.options.clone());
+        configure(&mut writer)?;
+        let mut replica = match self.replica.take() {
+            Some(replica) => replica,
+            None => return self.write_stream(stream, writer),
+        };
+
+        let result = self.write_mirrored(stream, writer, &mut replica, configure);
+        self.replica = Some(replica);
+        result
+    }
+
+    /// 将数据流同时写入当前存储和副本
+    fn write_mirrored(
+        &mut self, 
+        stream: impl Read, 
+        mut writer: Writer, 
+        replica: &mut Disk, 
+        configure: impl Fn(&mut Writer) -> Result<()>
+    ) -> Result<Writer> {
+        self.last_active = Instant::now();
+        let mut mirror = Writer::new(replica.tracks.clone(), replica.options.clone());
+        let result = configure(&mut mirror)
+            .and_then(|_| self.write_chunks(stream, &mut writer, Some((replica, &mut mirror))))
+            .and_then(|_| match writer.head == mirror.head && writer.layout == mirror.layout {
+                false => Err(KernelError::ReplicaDiverged.into()),
+                true => Ok(()),
+            });
+
+        // 读取或者写入失败，
+        // 或者两边的分片分布不一致时
+        // 删除两边已经写入的分片
+        if let Err(e) = result {
+            self.discard(&mut writer)?;
+            replica.discard(&mut mirror)?;
+            return Err(e);
+        }
+
+        let head = writer.head.unwrap();
+        if let Err(e) = self.charge(head, writer.written()) {
+            replica.discard(&mut mirror)?;
+            return Err(e);
+        }
+
+        if let Err(e) = replica.charge(mirror.head.unwrap(), mirror.written()) {
+            self.discard_object(head.0, head.1)?;
+            return Err(e);
+        }
+
+        Ok(writer)
+    }
+
+    /// 向写入流写入一块数据
+    ///
+    /// 需要创建新轨道时创建轨道，
+    /// 写入流返回完成时返回`true`
+    fn feed(&mut self, writer: &mut Writer, data: Option<&[u8]>) -> Result<bool> {
+        match writer.write(data)? {
+            Some(Callback::CreateTrack(track)) => self.create_track(track)?,
+            Some(Callback::Done) => return Ok(true),
+            _ => ()
+        }
+
+        Ok(false)
+    }
+
+    /// 丢弃写入流已经写入的分片
+    fn discard(&mut self, writer: &mut Writer) -> Result<()> {
+        if let Some((track, index)) = writer.abort()? {
+            self.remove_local(track, index)?;
+        }
+
+        Ok(())
+    }
+
+    /// 删除已经记录配额的数据
+    fn discard_object(&mut self, track: u16, index: u64) -> Result<()> {
+        self.uncharge(track, index)?;
+        self.remove_local(track, index)
+    }
+
+    /// 读取数据流
+    ///
+    /// 从头部分片开始读取全部数据，
+    /// 写入外部流中
+    #[rustfmt::skip]
+    fn read_stream(&mut self, mut stream: impl Write, track: u16, index: u64, lossy: Option<usize>) -> Result<()> {
+        self.check_head(track, index)?;
+        self.last_active = Instant::now();
+        if self.options.access_stats {
+            self.touch(track, index)?;
+        }
+
+        let mut reader = Reader::new(self.tracks.clone(), track, index);
+        reader.lossy = lossy;
+
+        // 无限循环
+        // 将轨道数据全部读取
+        // 写入外部流中
+    loop {
+        match reader.read()? {
+            Some(data) => stream.write_all(&data)?,
+            None => break
+        }
+    }
+
+        // 写入完成之后
+        // 清空尾部缓冲区，
+        // 将所有数据推入目的地
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// 写入数据流
+    ///
+    /// 读取外部流并通过写入流写入轨道，
+    /// 写入完成之后返回写入流
+    fn write_stream(&mut self, stream: impl Read, mut writer: Writer) -> Result<Writer> {
+        self.last_active = Instant::now();
+
+        // 读取或者写入失败时删除已经写入的分片
+        match self.write_chunks(stream, &mut writer, None) {
+            Ok(()) => {
+                self.charge(writer.head.unwrap(), writer.written())?;
+                Ok(writer)
+            },
+            Err(e) => {
+                if let Some((track, index)) = writer.abort()? {
+                    self.remove_local(track, index)?;
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// 记录写入配额
+    ///
+    /// 拒绝写入时删除刚写入的数据
+    fn charge(&mut self, head: (u16, u64), size: u64) -> Result<()> {
+        let quota = match self.quota.as_mut() {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        if quota.charge(size) {
+            return Ok(());
+        }
+
+        let limit = quota.limit;
+        self.remove_local(head.0, head.1)?;
+        Err(KernelError::QuotaExceeded(limit).into())
+    }
+
+    /// 释放删除数据的配额
+    fn uncharge(&mut self, track: u16, index: u64) -> Result<()> {
+        if self.quota.is_some() {
+            let size = self.object_size(track, index)?;
+            self.quota.as_mut().unwrap().release(size);
+        }
+
+        Ok(())
+    }
+
+    /// 写入全部分片
+    ///
+    /// 读取外部流并通过写入流写入轨道，
+    /// 指定副本时将同一块数据依次写入副本的写入流，
+    /// 两边的写入流都返回完成之后才结束
+    #[rustfmt::skip]
+    fn write_chunks(
+        &mut self, 
+        mut stream: impl Read, 
+        writer: &mut Writer, 
+        mut mirror: Option<(&mut Disk, &mut Writer)>
+    ) -> Result<()> {
+        let mut buffer = [0; 4096];
+        let mut state = State::Filling;
+        let mut pending = (true, mirror.is_some());
+
+        // 无限循环
+        // 读取外部源写入轨道
+    loop {
+        
+        // 读取外部流数据，
+        // 第一次读取到0即认为外部流已经结束，
+        // 之后不再读取外部流
+        let data = match state {
+            State::Filling => match stream.read(&mut buffer)? {
+                0 => {
+                    state = State::Finalizing;
+                    continue;
+                },
+                size => Some(&buffer[0..size]),
+            },
+            State::Finalizing => None,
+            State::Done => return Ok(()),
+        };
+        
+        // 处理写入返回，如创建新轨道，
+        // 如果写入流返回完成，说明这一边写入完成
+        if pending.0 && self.feed(writer, data)? {
+            pending.0 = false;
+        }
+
+        if let Some((replica, mirror)) = mirror.as_mut() {
+            if pending.1 && replica.feed(mirror, data)? {
+                pending.1 = false;
+            }
+        }
+
+        if pending == (false, false) {
+            state = State::Done;
+        }
+    }
+    }
+
+    /// 在当前存储中批量写入数据
+    fn write_batch_local(&mut self, objects: Vec<(u64, &mut dyn Read)>) -> Result<Vec<(u16, u64)>> {
+        let diff_size = self.options.chunk_size - HEADER_SIZE as u64;
+        let counts = objects
+            .iter()
+            .map(|(size, _)| size.div_ceil(diff_size).max(1) as usize)
+            .collect::<Vec<usize>>();
+
+        // 禁止跨轨道时每个数据的分片必须在同一个轨道，
+        // 所以只能逐个在当前轨道中预分配
+        let total = counts.iter().sum();
+        let mut chunks = Vec::with_capacity(total);
+        if self.options.spill {
+            if let Err(e) = self.alloc_batch(total, &mut chunks) {
+                let mut pool = Writer::new(self.tracks.clone(), self.options.clone());
+                pool.assign(chunks);
+                pool.abort()?;
+                return Err(e);
+            }
+        }
+
+        let mut chunks = VecDeque::from(chunks);
+        let mut heads = Vec::with_capacity(objects.len());
+        for ((_, stream), count) in objects.into_iter().zip(counts) {
+            let mut writer = Writer::new(self.tracks.clone(), self.options.clone());
+            let result = if self.options.spill {
+                writer.assign(chunks.drain(..count));
+                Ok(())
+            } else {
+                writer.reserve(count)
+            };
+
+            match result.and_then(|_| self.write_stream(stream, writer)) {
+                Ok(writer) => heads.push(writer.head.unwrap()),
+                Err(e) => {
+                    let mut pool = Writer::new(self.tracks.clone(), self.options.clone());
+                    pool.assign(chunks);
+                    pool.abort()?;
+                    for (track, index) in heads {
+                        self.uncharge(track, index)?;
+                        self.remove_local(track, index)?;
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(heads)
+    }
+
+    /// 在当前存储中分段并行写入数据
+    fn write_split_local(&mut self, data: &[u8], segments: usize) -> Result<(u16, u64)> {
+        self.last_active = Instant::now();
+        let diff_size = self.options.chunk_size as usize - HEADER_SIZE;
+        let total = data.len().div_ceil(diff_size).max(1);
+        let count = total.div_ceil(segments);
+
+        // 每段从上一段之后的轨道开始分配
+        let mut chunks = Vec::with_capacity(total);
+        let mut track = 1;
+        while chunks.len() < total {
+            let end = (chunks.len() + count).min(total);
+            if let Err(e) = self.alloc_segment(&mut track, end, &mut chunks) {
+                let mut pool = Writer::new(self.tracks.clone(), self.options.clone());
+                pool.assign(chunks);
+                pool.abort()?;
+                return Err(e);
+            }
+
+            track += 1;
+        }
+
+        match self.write_segments(data, &chunks) {
+            Ok(()) => {
+                self.charge(chunks[0], data.len() as u64)?;
+                Ok(chunks[0])
+            },
+            Err(e) => {
+                let mut pool = Writer::new(self.tracks.clone(), self.options.clone());
+                pool.assign(chunks);
+                pool.abort()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// 为分段分配分片
+    ///
+    /// 从`track`开始分配直到`chunks`达到`end`个，
+    /// 轨道空间不足时切换到下个轨道
+    fn alloc_segment(&mut self, track: &mut u16, end: usize, chunks: &mut Vec<(u16, u64)>) -> Result<()> {
+        while chunks.len() < end {
+            if !self.tracks.borrow().contains_key(track) {
+                self.create_track(*track)?;
+            }
+
+            let mut tracks = self.tracks.borrow_mut();
+            match tracks.get_mut(track).unwrap().alloc()? {
+                Some(index) => chunks.push((*track, index)),
+                None => *track += 1,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 并行写入分段
+    ///
+    /// 按照轨道编码所有分片，
+    /// 每个轨道由一个线程使用独立的文件句柄写入，
+    /// 线程崩溃时作为错误返回，
+    /// 头部分片最后通过轨道写入以便读回校验
+    fn write_segments(&mut self, data: &[u8], chunks: &[(u16, u64)]) -> Result<()> {
+        let diff_size = self.options.chunk_size as usize - HEADER_SIZE;
+        let mut groups: HashMap<u16, Vec<(u64, Bytes)>> = HashMap::new();
+        {
+            let mut tracks = self.tracks.borrow_mut();
+            for (i, (track, index)) in chunks.iter().enumerate().skip(1) {
+                let start = (i * diff_size).min(data.len());
+                let end = (start + diff_size).min(data.len());
+                let next = chunks.get(i + 1).copied();
+                let packet = tracks
+                    .get_mut(track)
+                    .unwrap()
+                    .encode(next, false, &data[start..end], *index)?;
+                groups.entry(*track).or_default().push((*index, packet));
+            }
+        }
+
+        let options = self.options.clone();
+        let path: &Path = options.path.as_ref();
+        let results = std::thread::scope(|scope| {
+            let workers = groups.into_iter().map(|(track, packets)| scope.spawn(move || {
+                let mut file = Fs::new(path.join(format!("{}.track", track)))?;
+                for (index, packet) in packets {
+                    file.write(&packet, index)?;
+                }
+
+                file.flush()
+            })).collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|_| Err(anyhow!("segment writer panicked"))))
+                .collect::<Vec<Result<()>>>()
+        });
+
+        for result in results {
+            result?;
+        }
+
+        let (track, index) = chunks[0];
+        let mut tracks = self.tracks.borrow_mut();
+        tracks
+            .get_mut(&track)
+            .unwrap()
+            .write(chunks.get(1).copied(), true, &data[..diff_size.min(data.len())], index)?;
+        for track in chunks.iter().map(|(track, _)| *track).collect::<HashSet<u16>>() {
+            tracks.get_mut(&track).unwrap().flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// 为批量写入分配分片
+    ///
+    /// 从第一个轨道开始分配`total`个分片，
+    /// 轨道空间不足时切换到下个轨道，
+    /// 分配完成之后按照轨道和位置排序，
+    /// 失败时已经分配的分片保留在`chunks`中由调用方释放
+    fn alloc_batch(&mut self, total: usize, chunks: &mut Vec<(u16, u64)>) -> Result<()> {
+        let mut track = 1;
+        while chunks.len() < total {
+            if !self.tracks.borrow().contains_key(&track) {
+                self.create_track(track)?;
+            }
+
+            let mut tracks = self.tracks.borrow_mut();
+            match tracks.get_mut(&track).unwrap().alloc()? {
+                Some(index) => chunks.push((track, index)),
+                None => track += 1,
+            }
+        }
+
+        chunks.sort_unstable();
+        Ok(())
+    }
+
+    /// 检查头部分片
+    ///
+    /// 删除数据时头部分片的标记被清除，
+    /// 指向已删除数据的外部引用返回`KernelError::NotFound`，
+    /// 而不是继续读取失效的链表，
+    /// 返回头部分片的分片头
+    fn check_head(&self, track: u16, index: u64) -> Result<Header> {
+        let mut tracks = self.tracks.borrow_mut();
+        let header = match tracks.get_mut(&track) {
+            Some(item) => item.header(index)?,
+            None => return Err(KernelError::MissingTrack(track).into()),
+        };
+
+        match header.is_head() {
+            false => Err(KernelError::NotFound(track, index).into()),
+            true => Ok(header),
+        }
+    }
+
+    /// 获取所有轨道的文件长度
+    fn track_sizes(&self) -> Result<HashMap<u16, u64>> {
+        let path: &Path = self.options.path.as_ref();
+        let mut sizes = HashMap::new();
+        for id in self.tracks.borrow().keys() {
+            let track_path = path.join(format!("{}.track", id));
+            sizes.insert(*id, std::fs::metadata(track_path)?.len());
+        }
+
+        Ok(sizes)
+    }
+
+    /// 创建所有轨道的校验任务
+    fn verify_tasks<'a>(&mut self, sizes: &'a HashMap<u16, u64>) -> Result<Vec<Task<'a>>> {
+        let mut tasks = Vec::new();
+        for (id, track) in self.tracks.borrow_mut().iter_mut() {
+            tasks.push(Task {
+                free: track.free_list()?.into_iter().collect(),
+                codec: Codec::new(self.options.clone()),
+                chunk_size: self.options.chunk_size,
+                id: *id,
+                sizes,
+            });
+        }
+
+        Ok(tasks)
+    }
+
+    /// 获取数据ID表
+    ///
+    /// 数据ID表在初始化时打开，
+    /// 没有初始化时返回`KernelError::NotInitialized`
+    fn objects(&mut self) -> Result<&mut Objects> {
+        match self.objects.as_mut() {
+            Some(objects) => Ok(objects),
+            None => Err(KernelError::NotInitialized.into()),
+        }
+    }
+
+    /// 记录数据读取
+    ///
+    /// 更新头部分片的读取统计
+    #[rustfmt::skip]
+    fn touch(&mut self, track: u16, index: u64) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_millis() as u64;
+        match self.tracks.borrow_mut().get_mut(&track) {
+            Some(track) => track.touch(index, now),
+            None => Ok(()),
+        }
+    }
+
+    /// 创建轨道
+    ///
+    /// 创建轨道类并初始化，
+    /// 将轨道添加到内部的轨道列表，
+    /// 轨道文件已经被其他写入创建时打开已有的轨道
+    fn create_track(&mut self, id: u16) -> Result<()> {
+        let track = Track::create(id, self.options.clone())?;
+        self.insert_track(id, track)
+    }
+
+    /// 打开轨道
+    ///
+    /// 打开目录中已有的轨道文件，
+    /// 文件头不完整时返回错误，不会等待或者覆盖
+    fn open_track(&mut self, id: u16) -> Result<()> {
+        let track = Track::new(id, self.options.clone())?;
+        self.insert_track(id, track)
+    }
+
+    /// 初始化轨道并添加到内部的轨道列表
+    #[rustfmt::skip]
+    fn insert_track(&mut self, id: u16, mut track: Track) -> Result<()> {
+        track.init()?;
+        self.tracks
+            .borrow_mut()
+            .insert(id, track);
+        Ok(())
+    }
+}
+
+/// 沿着分片链表逐个轨道删除
+///
+/// 链表指向不存在的轨道时返回`KernelError::MissingTrack`，
+/// 之前轨道中的分片已经被删除
+fn remove_chain(tracks: &mut HashMap<u16, Track>, track: u16, index: u64) -> Result<()> {
+    let mut next = Some((track, index));
+    let mut source = None;
+    while let Some((track_id, index)) = next {
+        let (last, link) = match (tracks.get_mut(&track_id), source) {
+            (Some(track), _) => track.remove(index)?,
+            (None, None) => return Err(KernelError::MissingTrack(track_id).into()),
+            (None, Some((source, offset))) => return Err(KernelError::MissingTrack(track_id))
+                .with_context(|| format!("linked from track {} offset {}", source, offset)),
+        };
+
+        source = Some((track_id, last));
+        next = link;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Disk, KernelError, KernelOptions, ObjectId, QuotaDecision, RingBuffer, HEADER_SIZE};
+    use crate::{CompactTrigger, IdleDefrag};
+    use std::io::Read;
+    use std::collections::HashSet;
+    use std::os::unix::fs::FileExt;
+    use std::fs::OpenOptions;
+    use std::time::Duration;
+    use std::path::Path;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn options(path: &Path, track_size: u64) -> KernelOptions {
+        KernelOptions::from(path.to_str().unwrap().to_string(), track_size)
+    }
+
+    fn open(options: KernelOptions) -> Disk {
+        let mut disk = Disk::new(Rc::new(options));
+        disk.init().unwrap();
+        disk
+    }
+
+    fn read(disk: &mut Disk, (track, index): (u16, u64)) -> Vec<u8> {
+        let mut out = Vec::new();
+        disk.read(&mut out, track, index).unwrap();
+        out
+    }
+
+    fn kind(error: anyhow::Error) -> KernelError {
+        error.downcast_ref::<KernelError>().cloned().unwrap()
+    }
+
+    fn payload(size: usize, seed: u8) -> Vec<u8> {
+        (0..size).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    /// 总是读取失败的外部流
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+    }
+
+    /// 按顺序返回预设内容的外部流，
+    /// 读完之后始终返回0
+    struct Flaky(Vec<Vec<u8>>);
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+
+            let part = self.0.remove(0);
+            buf[..part.len()].copy_from_slice(&part);
+            Ok(part.len())
+        }
+    }
+
+    #[test]
+    fn stale_generation_is_rejected_after_reuse() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 2));
+        let (track, index) = disk.write(&b"hello"[..]).unwrap();
+        let generation = disk.generation(track, index).unwrap();
+        disk.remove(track, index).unwrap();
+
+        // 轨道写满之后才会复用失效分片
+        disk.write(&b"other"[..]).unwrap();
+        assert_eq!(disk.write(&b"third"[..]).unwrap(), (track, index));
+
+        let error = disk.read_checked(Vec::new(), track, index, generation).unwrap_err();
+        assert_eq!(kind(error), KernelError::StaleHandle);
+
+        let mut out = Vec::new();
+        disk.read_checked(&mut out, track, index, generation.wrapping_add(1)).unwrap();
+        assert_eq!(out, b"third");
+    }
+
+    #[test]
+    fn stale_generation_survives_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let defrag = || {
+            let mut options = options(dir.path(), 1024 * 1024);
+            options.idle_defrag = Some(IdleDefrag { idle: Duration::from_millis(0), io_budget: 1024 });
+            options
+        };
+
+        let mut disk = open(defrag());
+        let (track, index) = disk.write(&b"hello"[..]).unwrap();
+        let generation = disk.generation(track, index).unwrap();
+        disk.remove(track, index).unwrap();
+        assert!(disk.maintain().unwrap());
+        assert_eq!(std::fs::metadata(dir.path().join("1.track")).unwrap().len(), 40);
+
+        // 截断之后重新打开轨道，
+        // 从尾部重新分配的分片代数仍然更大
+        drop(disk);
+        let mut disk = open(defrag());
+        assert_eq!(disk.write(&b"other"[..]).unwrap(), (track, index));
+        let error = disk.read_checked(Vec::new(), track, index, generation).unwrap_err();
+        assert_eq!(kind(error), KernelError::StaleHandle);
+        assert!(disk.generation(track, index).unwrap() > generation);
+    }
+
+    #[test]
+    fn compact_keeps_pinned_heads() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let heads = (0..8u8)
+            .map(|i| (disk.write(&payload(4067 * 2 + 100, i)[..]).unwrap(), i))
+            .collect::<Vec<_>>();
+        for (head, _) in heads.iter().step_by(2) {
+            disk.remove(head.0, head.1).unwrap();
+        }
+
+        let live = heads.into_iter().skip(1).step_by(2).collect::<Vec<_>>();
+        let pinned = [live[0].0, live[3].0].iter().copied().collect::<HashSet<_>>();
+        let track = dir.path().join("1.track");
+        let before = std::fs::metadata(&track).unwrap().len();
+        let moved = disk.compact(1, &pinned, |_, _| {}).unwrap();
+        assert!(std::fs::metadata(&track).unwrap().len() < before);
+        assert!(!moved.is_empty());
+        assert!(moved.iter().all(|(old, _)| !pinned.contains(old)));
+
+        // 迁移之后原位置不再是头部分片
+        for (head, seed) in live {
+            let new = match moved.iter().find(|(old, _)| *old == head) {
+                Some((_, new)) => {
+                    assert!(disk.read(Vec::new(), head.0, head.1).is_err());
+                    *new
+                },
+                None => head,
+            };
+
+            assert_eq!(read(&mut disk, new), payload(4067 * 2 + 100, seed));
+        }
+    }
+
+    #[test]
+    fn import_rechunks_exported_objects() {
+        let source = tempfile::tempdir().unwrap();
+        let mut options_a = options(source.path(), 1024 * 1024 * 64);
+        options_a.chunk_size = 64 * 1024;
+        let mut a = open(options_a);
+        let sizes = [0usize, 5, 65507, 65507 * 3 + 7, 300_000];
+        let heads = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| a.write(&payload(*size, i as u8)[..]).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut archive = Vec::new();
+        a.export(&mut archive).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let mut options_b = options(target.path(), 1024 * 1024 * 64);
+        options_b.chunk_size = 128 * 1024;
+        let mut b = open(options_b);
+        let map = b.import(&archive[..]).unwrap();
+        assert_eq!(map.len(), sizes.len());
+        for (i, head) in heads.iter().enumerate() {
+            let (_, new) = map.iter().find(|(old, _)| old == head).unwrap();
+            assert_eq!(read(&mut b, *new), payload(sizes[i], i as u8));
+            let chunks = (sizes[i] as u64).div_ceil(128 * 1024 - HEADER_SIZE as u64).max(1);
+            assert_eq!(b.object_layout(new.0, new.1).unwrap().len() as u64, chunks);
+        }
+    }
+
+    #[test]
+    fn access_stats_persist_in_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = || {
+            let mut options = options(dir.path(), 1024 * 1024);
+            options.access_stats = true;
+            options
+        };
+
+        let mut disk = open(stats());
+        let head = disk.write(&payload(10000, 1)[..]).unwrap();
+        assert_eq!(disk.object_stats(head.0, head.1).unwrap().hits, 0);
+        for _ in 0..3 {
+            assert_eq!(read(&mut disk, head), payload(10000, 1));
+        }
+
+        drop(disk);
+        let mut disk = open(stats());
+        let stats = disk.object_stats(head.0, head.1).unwrap();
+        assert_eq!(stats.hits, 3);
+        assert!(stats.accessed > 0);
+    }
+
+    #[test]
+    fn access_stats_disabled_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let head = disk.write(&payload(100, 1)[..]).unwrap();
+        read(&mut disk, head);
+        assert_eq!(disk.object_stats(head.0, head.1).unwrap().hits, 0);
+    }
+
+    #[test]
+    fn write_with_layout_matches_object_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 3));
+        let data = payload(4067 * 4 + 10, 1);
+        let (head, layout) = disk.write_with_layout(&data[..]).unwrap();
+        assert_eq!(layout.len(), 5);
+        assert_eq!(layout[0], head);
+        assert_eq!(layout.iter().map(|x| x.0).collect::<Vec<_>>(), vec![1, 1, 1, 2, 2]);
+        assert_eq!(disk.object_layout(head.0, head.1).unwrap(), layout);
+        assert_eq!(read(&mut disk, head), data);
+    }
+
+    #[test]
+    fn lossy_read_fills_missing_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 2));
+        let data = payload(4067 * 3, 3);
+        let head = disk.write(&data[..]).unwrap();
+        drop(disk);
+
+        std::fs::remove_file(dir.path().join("2.track")).unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 2));
+        let error = disk.read(Vec::new(), head.0, head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::MissingTrack(2));
+
+        let mut out = Vec::new();
+        disk.read_lossy(&mut out, head.0, head.1).unwrap();
+        assert_eq!(&out[..4067 * 2], &data[..4067 * 2]);
+        assert_eq!(&out[4067 * 2..], &[0u8; 4067][..]);
+    }
+
+    #[test]
+    fn lossy_read_continues_past_corrupt_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let data = payload(4067 * 3, 4);
+        let head = disk.write(&data[..]).unwrap();
+        let layout = disk.object_layout(head.0, head.1).unwrap();
+        drop(disk);
+
+        // 中间分片的数据长度损坏，
+        // 分片头仍然可以找到下个分片
+        let file = OpenOptions::new().write(true).open(dir.path().join("1.track")).unwrap();
+        file.write_all_at(&u32::MAX.to_be_bytes(), layout[1].1 + 8).unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let error = disk.read(Vec::new(), head.0, head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::InvalidSize(1, layout[1].1));
+
+        let mut out = Vec::new();
+        disk.read_lossy(&mut out, head.0, head.1).unwrap();
+        assert_eq!(out.len(), data.len());
+        assert_eq!(&out[..4067], &data[..4067]);
+        assert_eq!(&out[4067..4067 * 2], &[0u8; 4067][..]);
+        assert_eq!(&out[4067 * 2..], &data[4067 * 2..]);
+    }
+
+    #[test]
+    fn unaligned_offsets_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let (track, index) = disk.write(&payload(10000, 1)[..]).unwrap();
+        let unaligned = |error: anyhow::Error| kind(error) == KernelError::UnalignedOffset(index + 3);
+        assert!(unaligned(disk.read(Vec::new(), track, index + 3).unwrap_err()));
+        assert!(unaligned(disk.read_lossy(Vec::new(), track, index + 3).unwrap_err()));
+        assert!(unaligned(disk.read_checked(Vec::new(), track, index + 3, 0).unwrap_err()));
+        assert!(unaligned(disk.generation(track, index + 3).unwrap_err()));
+        assert!(unaligned(disk.object_stats(track, index + 3).unwrap_err()));
+        assert!(unaligned(disk.object_layout(track, index + 3).unwrap_err()));
+        assert!(unaligned(disk.object_size(track, index + 3).unwrap_err()));
+        assert!(unaligned(disk.remove(track, index + 3).unwrap_err()));
+        assert_eq!(read(&mut disk, (track, index)), payload(10000, 1));
+    }
+
+    #[test]
+    fn largest_objects_across_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 8));
+        let sizes = [100usize, 4067 * 6, 3, 4067 * 7 + 1, 9000, 4067 * 5];
+        let heads = sizes
+            .iter()
+            .map(|size| disk.write(&payload(*size, 0)[..]).unwrap())
+            .collect::<Vec<_>>();
+        assert!(heads.iter().any(|head| head.0 > 1));
+
+        let top = disk.largest_objects(3).unwrap();
+        assert_eq!(top, vec![
+            (heads[3], sizes[3] as u64),
+            (heads[1], sizes[1] as u64),
+            (heads[5], sizes[5] as u64),
+        ]);
+
+        assert!(disk.largest_objects(0).unwrap().is_empty());
+        assert_eq!(disk.largest_objects(100).unwrap().len(), sizes.len());
+    }
+
+    #[test]
+    fn object_id_follows_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let hole = disk.write(&payload(9000, 1)[..]).unwrap();
+        let id = disk.reserve_id().unwrap();
+        let data = payload(9000, 2);
+        disk.write_with_id(id, &data[..]).unwrap();
+        let error = disk.write_with_id(id, &data[..]).unwrap_err();
+        assert_eq!(kind(error), KernelError::InvalidId(id.0));
+
+        disk.remove(hole.0, hole.1).unwrap();
+        let moved = disk.compact(1, &HashSet::new(), |_, _| {}).unwrap();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(disk.object_head(id).unwrap(), Some(moved[0].1));
+        drop(disk);
+
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let mut out = Vec::new();
+        disk.read_id(&mut out, id).unwrap();
+        assert_eq!(out, data);
+        disk.remove_id(id).unwrap();
+        assert_eq!(disk.object_head(id).unwrap(), None);
+    }
+
+    #[test]
+    fn object_id_requires_init() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = Disk::new(Rc::new(options(dir.path(), 1024 * 1024)));
+        let error = disk.reserve_id().unwrap_err();
+        assert_eq!(kind(error), KernelError::NotInitialized);
+
+        disk.init().unwrap();
+        let id = disk.reserve_id().unwrap();
+        assert_eq!(disk.object_head(id).unwrap(), None);
+    }
+
+    #[test]
+    fn next_into_track_header_is_corrupt_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let head = disk.write(&payload(9000, 5)[..]).unwrap();
+        drop(disk);
+
+        let file = OpenOptions::new().write(true).open(dir.path().join("1.track")).unwrap();
+        file.write_all_at(&3u64.to_be_bytes(), head.1).unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let error = disk.read(Vec::new(), head.0, head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::CorruptChain(3));
+    }
+
+    #[test]
+    fn compact_reports_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024 * 64));
+        let heads = (0..200)
+            .map(|i| disk.write(&payload(4067 * 3, i as u8)[..]).unwrap())
+            .collect::<Vec<_>>();
+        for head in heads.iter().step_by(2) {
+            disk.remove(head.0, head.1).unwrap();
+        }
+
+        let mut calls = Vec::new();
+        let moved = disk.compact(1, &HashSet::new(), |done, total| calls.push((done, total))).unwrap();
+        assert!(calls.len() > 2);
+        assert!(calls.windows(2).all(|w| w[0].0 <= w[1].0 && w[0].1 == w[1].1));
+        let (done, total) = *calls.last().unwrap();
+        assert_eq!(done, total);
+        assert!(total >= moved.len() as u64 && total > 0);
+
+        // 没有需要迁移的分片时也以总数调用一次
+        let mut calls = Vec::new();
+        disk.compact(1, &HashSet::new(), |done, total| calls.push((done, total))).unwrap();
+        assert_eq!(calls, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn empty_object_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let empty = disk.write(&b""[..]).unwrap();
+        let full = disk.write(&payload(4067, 1)[..]).unwrap();
+        assert!(read(&mut disk, empty).is_empty());
+        assert_eq!(disk.object_size(empty.0, empty.1).unwrap(), 0);
+        assert_eq!(read(&mut disk, full), payload(4067, 1));
+        assert_eq!(disk.object_size(full.0, full.1).unwrap(), 4067);
+    }
+
+    #[test]
+    fn parallel_verify_matches_serial() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 8));
+        let heads = (0..12)
+            .map(|i| disk.write(&payload(4067 * 2 + 5, i)[..]).unwrap())
+            .collect::<Vec<_>>();
+        disk.remove(heads[3].0, heads[3].1).unwrap();
+        let clean = disk.verify().unwrap();
+        assert!(clean.errors.is_empty());
+        assert_eq!(clean, disk.verify_parallel(3).unwrap());
+
+        let victim = heads[7];
+        drop(disk);
+        let file = OpenOptions::new().write(true).open(dir.path().join(format!("{}.track", victim.0))).unwrap();
+        file.write_all_at(&(victim.1 + 5).to_be_bytes(), victim.1).unwrap();
+
+        let mut options = options(dir.path(), 40 + 4096 * 8);
+        options.verify_rate = Some(100000);
+        let mut disk = open(options);
+        let serial = disk.verify().unwrap();
+        assert_eq!(serial.errors, vec![(victim.0, victim.1, KernelError::UnalignedOffset(victim.1 + 5))]);
+        assert_eq!(serial.chunks, clean.chunks);
+        assert_eq!(serial, disk.verify_parallel(4).unwrap());
+    }
+
+    #[test]
+    fn read_into_ring_streams_to_consumer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let data = payload(4067 * 8 + 11, 9);
+        let head = disk.write(&data[..]).unwrap();
+        let mut ring = RingBuffer::new(1000);
+        let mut consumer = ring.clone();
+        let reader = std::thread::spawn(move || {
+            let mut out = Vec::new();
+            consumer.read_to_end(&mut out).map(|_| out)
+        });
+
+        disk.read_into_ring(head.0, head.1, &mut ring).unwrap();
+        assert_eq!(reader.join().unwrap().unwrap(), data);
+
+        // 读取失败时消费方收到错误，而不是提前结束的数据
+        let mut ring = RingBuffer::new(1000);
+        let mut consumer = ring.clone();
+        assert!(disk.read_into_ring(head.0, head.1 + 4096, &mut ring).is_err());
+        assert!(consumer.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn open_reader_streams_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 4));
+        let data = payload(4067 * 6 + 9, 4);
+        let head = disk.write(&data[..]).unwrap();
+
+        // 小于分片的读取缓冲区跨越分片边界
+        let mut stream = disk.open_reader(head.0, head.1).unwrap();
+        let mut out = Vec::new();
+        let mut buffer = [0u8; 1000];
+        loop {
+            match stream.read(&mut buffer).unwrap() {
+                0 => break,
+                size => out.extend_from_slice(&buffer[..size]),
+            }
+        }
+
+        assert_eq!(out, data);
+        let error = disk.open_reader(head.0, head.1 + 4096).err().unwrap();
+        assert_eq!(kind(error), KernelError::NotFound(head.0, head.1 + 4096));
+    }
+
+    #[test]
+    fn compact_trigger_by_free_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path(), 40 + 4096 * 400);
+        options.compact_trigger = Some(CompactTrigger { free_ratio: 0.5, free_count: 50 });
+        let mut disk = open(options);
+        let heads = (0..300)
+            .map(|i| disk.write(&payload(100, i as u8)[..]).unwrap())
+            .collect::<Vec<_>>();
+        assert!(disk.compaction_candidates().unwrap().is_empty());
+        assert!(disk.maybe_compact(&HashSet::new()).unwrap().is_empty());
+
+        // 失效比例只有0.2，但是失效分片数量超过阈值
+        for head in heads.iter().step_by(5) {
+            disk.remove(head.0, head.1).unwrap();
+        }
+
+        assert_eq!(disk.compaction_candidates().unwrap(), vec![1]);
+        assert!(!disk.maybe_compact(&HashSet::new()).unwrap().is_empty());
+        assert!(disk.compaction_candidates().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_annotated_reports_source_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 3));
+        let data = payload(4067 * 5, 7);
+        let head = disk.write(&data[..]).unwrap();
+        let chunks = disk
+            .read_annotated(head.0, head.1)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let positions = chunks.iter().map(|x| (x.0, x.1)).collect::<Vec<_>>();
+        assert_eq!(positions, disk.object_layout(head.0, head.1).unwrap());
+        assert_eq!(positions.iter().map(|x| x.0).collect::<Vec<_>>(), vec![1, 1, 1, 2, 2]);
+        assert_eq!(chunks.iter().flat_map(|x| x.2.to_vec()).collect::<Vec<_>>(), data);
+    }
+
+    #[test]
+    fn max_track_file_bytes_spreads_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path(), 1024 * 1024);
+        options.max_track_file_bytes = Some(40 + 4096 * 3 + 100);
+        let mut disk = open(options);
+        let data = payload(4067 * 7, 2);
+        let head = disk.write(&data[..]).unwrap();
+        assert_eq!(read(&mut disk, head), data);
+
+        let tracks = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map(|x| x == "track").unwrap_or(false))
+            .collect::<Vec<_>>();
+        assert_eq!(tracks.len(), 3);
+        for path in tracks {
+            assert!(std::fs::metadata(path).unwrap().len() <= 40 + 4096 * 3 + 100);
+        }
+    }
+
+    #[test]
+    fn spill_disabled_rejects_oversized_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut invalid = options(dir.path(), 40 + 4096 * 4);
+        invalid.spill = false;
+        invalid.max_object_size = Some(4067 * 5);
+        let error = Disk::new(Rc::new(invalid)).init().unwrap_err();
+        assert!(matches!(kind(error), KernelError::InvalidOptions(_)));
+
+        let mut options = options(dir.path(), 40 + 4096 * 4);
+        options.spill = false;
+        let mut disk = open(options);
+        let error = disk.write(&payload(4067 * 4 + 1, 0)[..]).unwrap_err();
+        assert_eq!(kind(error), KernelError::ObjectTooLarge(4067 * 4));
+        assert!(disk.heads().unwrap().is_empty());
+
+        let data = payload(4067 * 4, 1);
+        let head = disk.write(&data[..]).unwrap();
+        assert_eq!(read(&mut disk, head), data);
+    }
+
+    #[test]
+    fn max_object_size_is_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path(), 1024 * 1024);
+        options.max_object_size = Some(5000);
+        let mut disk = open(options);
+        let error = disk.write(&payload(5001, 0)[..]).unwrap_err();
+        assert_eq!(kind(error), KernelError::ObjectTooLarge(5000));
+        assert!(disk.heads().unwrap().is_empty());
+        let head = disk.write(&payload(5000, 1)[..]).unwrap();
+        assert_eq!(read(&mut disk, head), payload(5000, 1));
+    }
+
+    #[test]
+    fn write_hinted_preallocates_contiguous_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let track = dir.path().join("1.track");
+        let len = || std::fs::metadata(&track).unwrap().len();
+
+        // 预分配多余的分片被释放，
+        // 预分配不足时继续分配
+        let a = payload(4067 * 7, 1);
+        let first = disk.write_hinted(&a[..], 10).unwrap();
+        assert_eq!(len(), 40 + 4096 * 7);
+        let b = payload(4067 * 7, 2);
+        let second = disk.write_hinted(&b[..], 3).unwrap();
+        assert_eq!(len(), 40 + 4096 * 14);
+        let layout = disk.object_layout(second.0, second.1).unwrap();
+        assert!(layout.windows(2).all(|w| w[1].1 == w[0].1 + 4096));
+        assert_eq!(read(&mut disk, first), a);
+        assert_eq!(read(&mut disk, second), b);
+    }
+
+    #[test]
+    fn padding_waste_counts_partial_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        assert_eq!(disk.padding_waste(1).unwrap(), 0.0);
+        for i in 0..4 {
+            disk.write(&payload(4067 * 2, i)[..]).unwrap();
+        }
+
+        assert_eq!(disk.padding_waste(1).unwrap(), 0.0);
+        for i in 0..8 {
+            disk.write(&payload(67, i)[..]).unwrap();
+        }
+
+        let waste = disk.padding_waste(1).unwrap();
+        assert!((waste - (8.0 * 4000.0) / (16.0 * 4067.0)).abs() < 1e-9);
+        assert_eq!(kind(disk.padding_waste(9).unwrap_err()), KernelError::MissingTrack(9));
+    }
+
+    #[test]
+    fn replica_replays_writes_and_removes() {
+        let primary = tempfile::tempdir().unwrap();
+        let secondary = tempfile::tempdir().unwrap();
+        let mut disk = open(options(primary.path(), 40 + 4096 * 16));
+        disk.set_replica(open(options(secondary.path(), 40 + 4096 * 16))).unwrap();
+        let heads = (0..10)
+            .map(|i| disk.write(&payload(4067 * 3 + i, i as u8)[..]).unwrap())
+            .collect::<Vec<_>>();
+        disk.remove(heads[2].0, heads[2].1).unwrap();
+        let hinted = disk.write_hinted(&payload(5000, 88)[..], 4).unwrap();
+        let (laid_out, _) = disk.write_with_layout(&payload(9000, 99)[..]).unwrap();
+        drop(disk.take_replica());
+
+        let mut replica = open(options(secondary.path(), 40 + 4096 * 16));
+        assert_eq!(replica.heads().unwrap(), disk.heads().unwrap());
+        for (i, head) in heads.iter().enumerate().filter(|(i, _)| *i != 2) {
+            assert_eq!(read(&mut replica, *head), payload(4067 * 3 + i, i as u8));
+        }
+
+        assert_eq!(read(&mut replica, hinted), payload(5000, 88));
+        assert_eq!(read(&mut replica, laid_out), payload(9000, 99));
+    }
+
+    #[test]
+    fn diverged_replica_discards_both_copies() {
+        let primary = tempfile::tempdir().unwrap();
+        let secondary = tempfile::tempdir().unwrap();
+        let mut disk = open(options(primary.path(), 40 + 4096 * 16));
+        disk.set_replica(open(options(secondary.path(), 40 + 4096 * 2))).unwrap();
+
+        // 副本轨道更小，分片分布不一致
+        let error = disk.write_with_layout(&payload(4067 * 5, 1)[..]).unwrap_err();
+        assert_eq!(kind(error), KernelError::ReplicaDiverged);
+        assert!(disk.heads().unwrap().is_empty());
+        assert!(disk.take_replica().unwrap().heads().unwrap().is_empty());
+    }
+
+    #[test]
+    fn diverged_compaction_plan_moves_nothing() {
+        let primary = tempfile::tempdir().unwrap();
+        let secondary = tempfile::tempdir().unwrap();
+        let mut disk = open(options(primary.path(), 1024 * 1024));
+        disk.set_replica(open(options(secondary.path(), 1024 * 1024))).unwrap();
+        let heads = (0..3)
+            .map(|i| disk.write(&payload(100, i)[..]).unwrap())
+            .collect::<Vec<_>>();
+
+        // 只在副本上删除尾部数据，两边的失效分片不一致
+        disk.replica.as_mut().unwrap().remove(heads[2].0, heads[2].1).unwrap();
+        disk.remove(heads[0].0, heads[0].1).unwrap();
+
+        let error = disk.compact(1, &HashSet::new(), |_, _| {}).unwrap_err();
+        assert_eq!(kind(error), KernelError::ReplicaDiverged);
+        assert_eq!(read(&mut disk, heads[1]), payload(100, 1));
+        assert_eq!(read(&mut disk, heads[2]), payload(100, 2));
+
+        let mut replica = disk.take_replica().unwrap();
+        assert_eq!(read(&mut replica, heads[1]), payload(100, 1));
+        assert_eq!(replica.heads().unwrap(), vec![heads[1]]);
+    }
+
+    #[test]
+    fn failed_replica_write_discards_primary() {
+        let primary = tempfile::tempdir().unwrap();
+        let secondary = tempfile::tempdir().unwrap();
+        let mut disk = open(options(primary.path(), 1024 * 1024));
+        let mut replica = open(options(secondary.path(), 1024 * 1024));
+        replica.set_quota(100, |_, _| QuotaDecision::Reject).unwrap();
+        disk.set_replica(replica).unwrap();
+
+        // 副本拒绝写入时，当前存储也不保留数据
+        let error = disk.write(&payload(5000, 1)[..]).unwrap_err();
+        assert_eq!(kind(error), KernelError::QuotaExceeded(100));
+        assert!(disk.heads().unwrap().is_empty());
+        let error = disk.write_split(&payload(5000, 1)[..], 2).unwrap_err();
+        assert_eq!(kind(error), KernelError::QuotaExceeded(100));
+        assert!(disk.heads().unwrap().is_empty());
+        assert!(disk.take_replica().unwrap().heads().unwrap().is_empty());
+    }
+
+    #[test]
+    fn swap_repoints_object_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let id = disk.reserve_id().unwrap();
+        let old = payload(4067 * 3, 1);
+        disk.write_with_id(id, &old[..]).unwrap();
+        let old_head = disk.object_head(id).unwrap().unwrap();
+
+        // 替换之前打开的读取流可以读完旧内容
+        let mut reader = disk.read_annotated(old_head.0, old_head.1);
+        let mut out = reader.next().unwrap().unwrap().2.to_vec();
+        let new = payload(4067 * 5 + 3, 2);
+        disk.swap(id, &new[..]).unwrap();
+        assert_ne!(disk.object_head(id).unwrap(), Some(old_head));
+        disk.write(&payload(4067 * 4, 3)[..]).unwrap();
+        for chunk in reader.by_ref() {
+            out.extend_from_slice(&chunk.unwrap().2);
+        }
+
+        assert_eq!(out, old);
+        assert_eq!(read(&mut disk, old_head), old);
+        let mut out = Vec::new();
+        disk.read_id(&mut out, id).unwrap();
+        assert_eq!(out, new);
+
+        // 最后一个读取流释放之后删除旧内容
+        drop(reader);
+        let error = disk.read(Vec::new(), old_head.0, old_head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::NotFound(old_head.0, old_head.1));
+        let mut free = disk.tracks.borrow_mut().get_mut(&old_head.0).unwrap().free_list().unwrap();
+        free.sort_unstable();
+        assert_eq!(free.len(), 3);
+        assert_eq!(free[0], old_head.1);
+
+        // 没有读取流时立即删除
+        let current = disk.object_head(id).unwrap().unwrap();
+        disk.swap(id, &new[..]).unwrap();
+        let error = disk.read(Vec::new(), current.0, current.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::NotFound(current.0, current.1));
+
+        // 写入失败时保留原来的数据
+        let heads = disk.heads().unwrap();
+        assert!(disk.swap(id, Broken).is_err());
+        assert_eq!(disk.heads().unwrap(), heads);
+        let mut out = Vec::new();
+        disk.read_id(&mut out, id).unwrap();
+        assert_eq!(out, new);
+        assert!(disk.swap(ObjectId(99), &b""[..]).is_err());
+    }
+
+    #[test]
+    fn read_until_finds_delimiter_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let mut data = vec![b'a'; 4067 * 3];
+        let at = 4067 - 2;
+        data[at..at + 4].copy_from_slice(b"##!#");
+        let head = disk.write(&data[..]).unwrap();
+
+        // 分隔符跨越第一个和第二个分片
+        let mut out = Vec::new();
+        assert_eq!(disk.read_until(head.0, head.1, b"##!#", &mut out).unwrap(), at as u64 + 4);
+        assert_eq!(out, &data[..at + 4]);
+
+        let mut out = Vec::new();
+        assert_eq!(disk.read_until(head.0, head.1, b"missing", &mut out).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn read_until_checks_head_before_touch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path(), 1024 * 1024);
+        options.access_stats = true;
+        let mut disk = open(options);
+        let head = disk.write(&payload(9000, 1)[..]).unwrap();
+        let body = disk.object_layout(head.0, head.1).unwrap()[1];
+        let before = std::fs::read(dir.path().join("1.track")).unwrap();
+
+        // 非头部分片不会被记录访问统计
+        let error = disk.read_until(body.0, body.1, b"\n", Vec::new()).unwrap_err();
+        assert_eq!(kind(error), KernelError::NotFound(body.0, body.1));
+        assert_eq!(std::fs::read(dir.path().join("1.track")).unwrap(), before);
+    }
+
+    #[test]
+    fn strict_flags_reject_unknown_bits() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let head = disk.write(&payload(100, 1)[..]).unwrap();
+        drop(disk);
+
+        let file = OpenOptions::new().read(true).write(true).open(dir.path().join("1.track")).unwrap();
+        let mut flags = [0u8; 1];
+        file.read_exact_at(&mut flags, head.1 + 16).unwrap();
+        file.write_all_at(&[flags[0] | 0x80], head.1 + 16).unwrap();
+
+        // 默认忽略未知的标记位
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        assert_eq!(read(&mut disk, head), payload(100, 1));
+        drop(disk);
+
+        let mut strict = options(dir.path(), 1024 * 1024);
+        strict.strict_flags = true;
+        let mut disk = open(strict);
+        let error = disk.read(Vec::new(), head.0, head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::UnknownFlags(head.1));
+    }
+
+    #[test]
+    fn write_batch_lays_objects_out_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024 * 4));
+        let objects = (0..100)
+            .map(|i| payload(4067 * (1 + i % 3) - (i % 7) * 10, i as u8))
+            .collect::<Vec<_>>();
+        let heads = disk.write_batch(objects.iter().map(|x| (x.len() as u64, &x[..])).collect()).unwrap();
+        assert_eq!(heads.len(), objects.len());
+
+        // 整批数据的分片按照顺序连续排列
+        let mut layout = Vec::new();
+        for (head, data) in heads.iter().zip(objects.iter()) {
+            assert_eq!(&read(&mut disk, *head), data);
+            layout.extend(disk.object_layout(head.0, head.1).unwrap());
+        }
+
+        assert!(layout.windows(2).all(|w| w[0].0 == w[1].0 && w[1].1 == w[0].1 + 4096));
+    }
+
+    #[test]
+    fn write_batch_fills_holes_in_position_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 40 + 4096 * 40));
+        let singles = (0..40u8).map(|i| disk.write(&payload(100, i)[..]).unwrap()).collect::<Vec<_>>();
+        for head in singles.iter().rev().step_by(2) {
+            disk.remove(head.0, head.1).unwrap();
+        }
+
+        let objects = (0..10).map(|i| payload(4067 * 2, i)).collect::<Vec<_>>();
+        let heads = disk.write_batch(objects.iter().map(|x| (x.len() as u64, &x[..])).collect()).unwrap();
+        let mut layout = Vec::new();
+        for (head, data) in heads.iter().zip(objects.iter()) {
+            assert_eq!(&read(&mut disk, *head), data);
+            layout.extend(disk.object_layout(head.0, head.1).unwrap());
+        }
+
+        assert!(layout.windows(2).all(|w| w[0] < w[1]));
+
+        // 任何数据写入失败时整批删除
+        let before = disk.heads().unwrap();
+        let data = payload(100, 1);
+        let batch: Vec<(u64, Box<dyn Read>)> = vec![(100, Box::new(&data[..])), (100, Box::new(Broken))];
+        assert!(disk.write_batch(batch).is_err());
+        assert_eq!(disk.heads().unwrap(), before);
+    }
+
+    #[test]
+    fn freed_head_is_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = options(dir.path(), 1024 * 1024);
+        options.access_stats = true;
+        let mut disk = open(options);
+        let keep = disk.write(&payload(100, 9)[..]).unwrap();
+        let head = disk.write(&payload(9000, 1)[..]).unwrap();
+        disk.write(&payload(100, 8)[..]).unwrap();
+        disk.remove(head.0, head.1).unwrap();
+
+        let not_found = KernelError::NotFound(head.0, head.1);
+        assert_eq!(kind(disk.read(Vec::new(), head.0, head.1).unwrap_err()), not_found);
+        assert_eq!(kind(disk.read_lossy(Vec::new(), head.0, head.1).unwrap_err()), not_found);
+        assert_eq!(kind(disk.object_size(head.0, head.1).unwrap_err()), not_found);
+        assert_eq!(kind(disk.object_stats(head.0, head.1).unwrap_err()), not_found);
+        assert_eq!(kind(disk.generation(head.0, head.1).unwrap_err()), not_found);
+        assert_eq!(kind(disk.remove(head.0, head.1).unwrap_err()), not_found);
+        assert_eq!(read(&mut disk, keep), payload(100, 9));
+    }
+
+    #[test]
+    fn write_split_spreads_segments_over_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024 * 16));
+        disk.write(&payload(300, 5)[..]).unwrap();
+        let data = payload(4067 * 401 + 17, 7);
+        let head = disk.write_split(&data[..], 4).unwrap();
+        let mut tracks = disk
+            .object_layout(head.0, head.1)
+            .unwrap()
+            .iter()
+            .map(|x| x.0)
+            .collect::<Vec<_>>();
+        tracks.dedup();
+        assert_eq!(tracks, vec![1, 2, 3, 4]);
+        assert_eq!(read(&mut disk, head), data);
+        drop(disk);
+
+        let mut disk = open(options(dir.path(), 1024 * 1024 * 16));
+        assert_eq!(read(&mut disk, head), data);
+        assert!(disk.verify().unwrap().errors.is_empty());
+        let empty = disk.write_split(&b""[..], 4).unwrap();
+        assert!(read(&mut disk, empty).is_empty());
+    }
+
+    #[test]
+    fn second_init_is_locked_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let locked = || {
+            let mut options = options(dir.path(), 1024 * 1024);
+            options.lock = true;
+            options
+        };
+
+        let disk = open(locked());
+        let error = Disk::new(Rc::new(locked())).init().unwrap_err();
+        assert_eq!(kind(error), KernelError::AlreadyLocked);
+
+        // 所有轨道关闭之后释放锁
+        drop(disk);
+        assert!(!dir.path().join(".lock").exists());
+        open(locked());
+    }
+
+    #[test]
+    fn deferred_bitmap_rebuild_serves_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let bitmap = || {
+            let mut options = options(dir.path(), 1024 * 1024);
+            options.free_bitmap = true;
+            options
+        };
+
+        let mut disk = open(bitmap());
+        let heads = (0..4).map(|i| disk.write(&payload(5000, i)[..]).unwrap()).collect::<Vec<_>>();
+        disk.remove(heads[1].0, heads[1].1).unwrap();
+        std::mem::forget(disk);
+
+        let mut options = bitmap();
+        options.bitmap_rebuild_budget = Some(Duration::ZERO);
+        let mut disk = open(options);
+        assert!(disk.is_rebuilding());
+        assert_eq!(read(&mut disk, heads[2]), payload(5000, 2));
+        assert!(disk.is_rebuilding());
+
+        disk.remove(heads[2].0, heads[2].1).unwrap();
+        assert!(!disk.is_rebuilding());
+        assert_eq!(read(&mut disk, heads[3]), payload(5000, 3));
+    }
+
+    #[test]
+    fn first_empty_read_ends_the_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+
+        // 第一次读取到0之后不再读取，
+        // 后面的数据不属于这个对象
+        let first = payload(3000, 1);
+        let stream = Flaky(vec![first.clone(), first.clone(), vec![], payload(100, 2)]);
+        let head = disk.write(stream).unwrap();
+        assert_eq!(read(&mut disk, head), [&first[..], &first[..]].concat());
+        assert_eq!(disk.object_layout(head.0, head.1).unwrap().len(), 2);
+
+        let head = disk.write(Flaky(vec![vec![], payload(100, 2)])).unwrap();
+        assert!(read(&mut disk, head).is_empty());
+    }
+
+    #[test]
+    fn quota_callback_decides_overflowing_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let old = disk.write(&payload(1000, 1)[..]).unwrap();
+
+        let fired = Rc::new(Cell::new(0));
+        let allow = Rc::new(Cell::new(false));
+        let (counter, decision) = (fired.clone(), allow.clone());
+        disk.set_quota(10_000, move |used, limit| {
+            assert!(used > limit);
+            counter.set(counter.get() + 1);
+            match decision.get() {
+                true => QuotaDecision::Allow,
+                false => QuotaDecision::Reject,
+            }
+        }).unwrap();
+
+        // 配额从已有数据开始统计
+        assert_eq!(disk.quota_usage(), Some((1000, 10_000)));
+        disk.write(&payload(8000, 2)[..]).unwrap();
+        assert_eq!(fired.get(), 0);
+
+        // 拒绝时丢弃刚写入的数据
+        let heads = disk.heads().unwrap();
+        let error = disk.write(&payload(5000, 3)[..]).unwrap_err();
+        assert_eq!(kind(error), KernelError::QuotaExceeded(10_000));
+        assert_eq!(fired.get(), 1);
+        assert_eq!(disk.heads().unwrap(), heads);
+        assert_eq!(disk.quota_usage(), Some((9000, 10_000)));
+
+        allow.set(true);
+        let head = disk.write(&payload(5000, 3)[..]).unwrap();
+        assert_eq!(read(&mut disk, head), payload(5000, 3));
+        assert_eq!(fired.get(), 2);
+        assert_eq!(disk.quota_usage(), Some((14_000, 10_000)));
+
+        disk.remove(old.0, old.1).unwrap();
+        assert_eq!(disk.quota_usage(), Some((13_000, 10_000)));
+    }
+
+    #[test]
+    fn link_to_missing_track_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let head = disk.write(&payload(5000, 1)[..]).unwrap();
+        drop(disk);
+
+        // 头部分片指向一个不存在的轨道
+        let path = dir.path().join(format!("{}.track", head.0));
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all_at(&99u16.to_be_bytes(), head.1 + 12).unwrap();
+
+        let mut disk = open(options(dir.path(), 1024 * 1024));
+        let error = disk.read(Vec::new(), head.0, head.1).unwrap_err();
+        let source = format!("track {} offset {}", head.0, head.1);
+        assert!(format!("{:#}", error).contains(&source), "{:#}", error);
+        assert_eq!(kind(error), KernelError::MissingTrack(99));
+
+        let mut out = Vec::new();
+        disk.read_lossy(&mut out, head.0, head.1).unwrap();
+        assert_eq!(out.len(), 4067 * 2);
+
+        let error = disk.object_layout(head.0, head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::MissingTrack(99));
+        let error = disk.object_size(head.0, head.1).unwrap_err();
+        assert!(format!("{:#}", error).contains(&source), "{:#}", error);
+        assert_eq!(kind(error), KernelError::MissingTrack(99));
+        let error = disk.remove(head.0, head.1).unwrap_err();
+        assert_eq!(kind(error), KernelError::MissingTrack(99));
     }
 }
@@ -0,0 +1,212 @@
+use super::{Fs, Head, KernelOptions};
+use anyhow::Result;
+use std::path::Path;
+use bytes::{
+    Buf,
+    BufMut,
+    BytesMut
+};
+
+use std::collections::{
+    BTreeSet,
+    HashMap,
+    HashSet
+};
+
+/// 数据ID
+///
+/// 数据的稳定编号，
+/// 数据位置发生变化（比如压缩）时保持不变
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(pub u64);
+
+/// 数据ID表
+///
+/// ID表保存在存储目录的`objects`文件中，
+/// 每个ID占用固定10字节，保存头部分片所在的轨道和位置，
+/// 全部为0表示ID未使用，
+/// 已预留但未写入的ID只保存在内存中，
+/// 所以重新打开之后会被自动回收
+pub struct Objects {
+    table: HashMap<u64, (u16, u64)>,
+    reserved: HashSet<u64>,
+    free: BTreeSet<u64>,
+    size: u64,
+    file: Fs,
+}
+
+impl Objects {
+    /// 打开ID表
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Objects, KernelOptions};
+    ///
+    /// let options = KernelOptions::from(
+    ///     "./.static".to_string(),
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// let objects = Objects::new(&options).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(options: &KernelOptions) -> Result<Self> {
+        let path: &Path = options.path.as_ref();
+        let mut file = Fs::new(path.join("objects"))?;
+        let size = file.stat()?.len() / 10;
+        let mut table = HashMap::new();
+        let mut free = BTreeSet::new();
+
+        // 读取全部ID，
+        // 未使用的ID进入空闲列表
+        let mut buffer = vec![0u8; (size * 10) as usize];
+        file.intact_read(&mut buffer, 0)?;
+        let mut packet = &buffer[..];
+        for id in 0..size {
+            let track = packet.get_u16();
+            let index = packet.get_u64();
+            if index == 0 {
+                free.insert(id);
+            } else {
+                table.insert(id, (track, index));
+            }
+        }
+
+        Ok(Self {
+            reserved: HashSet::new(),
+            table,
+            free,
+            size,
+            file,
+        })
+    }
+
+    /// 预留ID
+    ///
+    /// 优先复用最小的空闲ID
+    pub fn reserve(&mut self) -> ObjectId {
+        let id = match self.free.iter().next().copied() {
+            Some(id) => {
+                self.free.remove(&id);
+                id
+            }
+            None => {
+                self.size += 1;
+                self.size - 1
+            }
+        };
+
+        self.reserved.insert(id);
+        ObjectId(id)
+    }
+
+    /// 检查ID是否已经预留
+    pub fn is_reserved(&self, id: ObjectId) -> bool {
+        self.reserved.contains(&id.0)
+    }
+
+    /// 回收预留的ID
+    ///
+    /// 只能回收还未写入的ID，
+    /// 返回是否回收成功
+    pub fn release(&mut self, id: ObjectId) -> bool {
+        let released = self.reserved.remove(&id.0);
+        if released {
+            self.free.insert(id.0);
+        }
+
+        released
+    }
+
+    /// 获取ID对应的头部分片
+    pub fn get(&self, id: ObjectId) -> Option<(u16, u64)> {
+        self.table.get(&id.0).copied()
+    }
+
+    /// 提交ID
+    ///
+    /// 将ID指向给定的头部分片并保存
+    pub fn commit(&mut self, id: ObjectId, head: (u16, u64)) -> Result<()> {
+        self.write(id.0, head)?;
+        self.reserved.remove(&id.0);
+        self.table.insert(id.0, head);
+        Ok(())
+    }
+
+    /// 删除ID
+    ///
+    /// 返回ID原来指向的头部分片
+    pub fn remove(&mut self, id: ObjectId) -> Result<Option<(u16, u64)>> {
+        let head = self.table.remove(&id.0);
+        if head.is_some() {
+            self.write(id.0, (0, 0))?;
+            self.free.insert(id.0);
+        }
+
+        Ok(head)
+    }
+
+    /// 更新被迁移的头部分片
+    pub fn remap(&mut self, moved: &[(Head, Head)]) -> Result<()> {
+        let moved = moved.iter().copied().collect::<HashMap<_, _>>();
+        let mut changed = Vec::new();
+        for (id, head) in self.table.iter_mut() {
+            if let Some(new_head) = moved.get(head) {
+                *head = *new_head;
+                changed.push((*id, *new_head));
+            }
+        }
+
+        for (id, head) in changed {
+            self.write(id, head)?;
+        }
+
+        Ok(())
+    }
+
+    /// 写入ID表项
+    fn write(&mut self, id: u64, (track, index): (u16, u64)) -> Result<()> {
+        let mut packet = BytesMut::new();
+        packet.put_u16(track);
+        packet.put_u64(index);
+        self.file.write(&packet, id * 10)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KernelOptions, ObjectId, Objects};
+
+    #[test]
+    fn reserved_ids_are_reclaimed_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = KernelOptions::from(dir.path().to_str().unwrap().to_string(), 1024 * 1024);
+        let mut objects = Objects::new(&options).unwrap();
+        let a = objects.reserve();
+        let b = objects.reserve();
+        let c = objects.reserve();
+        assert_eq!((a, b, c), (ObjectId(0), ObjectId(1), ObjectId(2)));
+        assert!(objects.release(b));
+        assert!(!objects.release(b));
+        assert_eq!(objects.reserve(), b);
+
+        objects.commit(a, (1, 40)).unwrap();
+        objects.commit(c, (2, 4136)).unwrap();
+        assert!(!objects.release(a));
+        objects.remap(&[((2, 4136), (1, 8232))]).unwrap();
+        drop(objects);
+
+        // 只有已经提交的ID会保存，
+        // 预留但未写入的ID重新打开之后回收
+        let mut objects = Objects::new(&options).unwrap();
+        assert_eq!(objects.get(a), Some((1, 40)));
+        assert_eq!(objects.get(b), None);
+        assert_eq!(objects.get(c), Some((1, 8232)));
+        assert!(!objects.is_reserved(b));
+        assert_eq!(objects.reserve(), b);
+        assert_eq!(objects.remove(a).unwrap(), Some((1, 40)));
+        assert_eq!(objects.reserve(), a);
+    }
+}
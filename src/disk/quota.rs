@@ -0,0 +1,78 @@
+/// 超出配额时的处理
+///
+/// `Reject` 拒绝写入，返回`KernelError::QuotaExceeded`  
+/// `Allow` 允许写入，由回调自行记录警告
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaDecision {
+    Reject,
+    Allow,
+}
+
+/// 存储配额
+///
+/// 按照数据长度统计已经使用的字节数，
+/// 写入之后超出配额时由回调决定是否保留数据，
+/// 回调参数为写入之后的使用量和配额.
+pub struct Quota {
+    pub limit: u64,
+    pub used: u64,
+    callback: Box<dyn FnMut(u64, u64) -> QuotaDecision>,
+}
+
+impl Quota {
+    /// 创建配额
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Quota, QuotaDecision};
+    ///
+    /// let quota = Quota::new(1024, 0, |_, _| QuotaDecision::Reject);
+    /// ```
+    pub fn new(limit: u64, used: u64, callback: impl FnMut(u64, u64) -> QuotaDecision + 'static) -> Self {
+        Self {
+            callback: Box::new(callback),
+            limit,
+            used,
+        }
+    }
+
+    /// 记录写入
+    ///
+    /// 返回是否允许写入，
+    /// 不允许时使用量不变
+    pub fn charge(&mut self, size: u64) -> bool {
+        let used = self.used + size;
+        if used > self.limit && (self.callback)(used, self.limit) == QuotaDecision::Reject {
+            return false;
+        }
+
+        self.used = used;
+        true
+    }
+
+    /// 记录删除
+    pub fn release(&mut self, size: u64) {
+        self.used = self.used.saturating_sub(size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quota, QuotaDecision};
+
+    #[test]
+    fn rejected_charge_keeps_usage() {
+        let mut quota = Quota::new(100, 90, |used, limit| {
+            assert_eq!((used, limit), (120, 100));
+            QuotaDecision::Reject
+        });
+
+        assert!(quota.charge(10));
+        assert!(!quota.charge(20));
+        assert_eq!(quota.used, 100);
+
+        quota.release(150);
+        assert_eq!(quota.used, 0);
+    }
+}
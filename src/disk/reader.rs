@@ -1,16 +1,33 @@
-use super::{AllocMap, Tracks};
-use anyhow::Result;
+use super::{remove_chain, Head, KernelError, Tracks};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::cell::RefCell;
+use std::rc::Rc;
 
 /// 读取流
 ///
 /// 从轨道中读取数据，
+/// 沿着分片链表读取，
 /// 游标由内部维护
+///
+/// #### lossy
+/// 容错读取时后续分片丢失的填充长度，
+/// 设置之后后续分片无法读取时，
+/// 使用等长的0代替分片数据，
+/// 分片头还可以解码时从下个分片继续读取，
+/// 分片本身不可达时下个分片位置无法得知，
+/// 所以填充之后读取结束
+///
+/// #### previous
+/// 上个分片所在的轨道和位置，
+/// 下个分片的轨道不存在时作为错误的上下文
 pub struct Reader {
-    alloc_map: AllocMap,
-    track_index: usize,
-    alloc_size: usize,
-    track_id: usize,
+    pub lossy: Option<usize>,
+    previous: Option<(u16, u64)>,
+    next: Option<(u16, u64)>,
     tracks: Tracks,
+    head: bool,
 }
 
 impl Reader {
@@ -22,14 +39,14 @@ impl Reader {
     /// use super::Reader;
     /// use std::collections::HashMap;
     ///
-    /// let reader = Reader::new(HashMap::new(), HashMap::new());
+    /// let reader = Reader::new(HashMap::new(), 1, 40);
     /// ```
-    pub fn new(tracks: Tracks, alloc_map: AllocMap) -> Self {
+    pub fn new(tracks: Tracks, track: u16, index: u64) -> Self {
         Self {
-            alloc_size: alloc_map.len(),
-            track_index: 0,
-            track_id: 0,
-            alloc_map,
+            next: Some((track, index)),
+            previous: None,
+            lossy: None,
+            head: true,
             tracks,
         }
     }
@@ -42,42 +59,204 @@ impl Reader {
     /// use super::Reader;
     /// use std::collections::HashMap;
     ///
-    /// let reader = Reader::new(HashMap::new(), HashMap::new());
+    /// let reader = Reader::new(HashMap::new(), 1, 40);
     /// let data = reader.read().unwrap();
     /// ```
-    #[rustfmt::skip]
     pub fn read(&mut self) -> Result<Option<Vec<u8>>> {
-        
-        // 如果轨道遍历完成
+        Ok(self.read_annotated()?.map(|(_, _, data)| data))
+    }
+
+    /// 读取数据以及分片位置
+    ///
+    /// 返回分片所在的轨道和位置以及分片数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Reader;
+    /// use std::collections::HashMap;
+    ///
+    /// let reader = Reader::new(HashMap::new(), 1, 40);
+    /// let (track, index, data) = reader.read_annotated().unwrap().unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn read_annotated(&mut self) -> Result<Option<(u16, u64, Vec<u8>)>> {
+
+        // 如果链表遍历完成
         // 则返回`None`表示读取为空
-        if self.track_id >= self.alloc_size {
-            return Ok(None);
-        }
+        let (track_id, index) = match self.next {
+            Some(next) => next,
+            None => return Ok(None),
+        };
 
-        // 获取轨道分配表索引
         // 获取分片数据内容
-        let (track_id, list) = self.alloc_map.get(self.track_id).unwrap();
+        // 并将游标移动到下个分片
+        let head = std::mem::replace(&mut self.head, false);
         let mut tracks = self.tracks.borrow_mut();
-        let track = tracks.get_mut(&track_id).unwrap();
-        let index = list.get(self.track_index).unwrap();
-        let (next, chunk) = track.read(*index)?;
-        
-        // 如果没有后续分片
-        // 则返回`None`表示读取为空
-        if let None = next {
-            return Ok(None);
+        let chunk = match (tracks.get_mut(&track_id), self.previous) {
+            (Some(track), _) => track.read(index),
+            (None, None) => Err(KernelError::MissingTrack(track_id).into()),
+            (None, Some((source, offset))) => Err(KernelError::MissingTrack(track_id))
+                .with_context(|| format!("linked from track {} offset {}", source, offset)),
+        };
+
+        // 容错读取时头部分片必须有效，
+        // 后续分片丢失时使用0填充，
+        // 分片头可以解码时继续读取下个分片
+        let chunk = match (chunk, self.lossy) {
+            (Ok(chunk), _) => chunk,
+            (Err(e), Some(size)) if !head && is_lost(&e) => {
+                self.next = tracks
+                    .get_mut(&track_id)
+                    .and_then(|track| track.header(index).ok())
+                    .and_then(|header| header.next);
+                self.previous = Some((track_id, index));
+                return Ok(Some((track_id, index, vec![0u8; size])));
+            },
+            (Err(e), _) => return Err(e),
+        };
+
+        self.previous = Some((track_id, index));
+        self.next = chunk.next;
+        Ok(Some((
+            track_id,
+            index,
+            chunk.data.to_vec()
+        )))
+    }
+}
+
+/// 分片数据流
+///
+/// 按照链表顺序逐个读取分片，
+/// 内部只保留当前分片的数据，
+/// 内存占用不超过一个分片，
+/// 数据流存在期间持有读取租约
+pub struct ReadStream {
+    reader: Reader,
+    chunk: Vec<u8>,
+    cursor: usize,
+    _lease: Lease,
+}
+
+impl ReadStream {
+    /// 创建分片数据流
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Lease, Reader, Readers, ReadStream};
+    /// use std::collections::HashMap;
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let tracks = Rc::new(RefCell::new(HashMap::new()));
+    /// let readers = Rc::new(RefCell::new(Readers::default()));
+    /// let lease = Lease::new(readers, tracks.clone(), (1, 40));
+    /// let stream = ReadStream::new(Reader::new(tracks, 1, 40), lease);
+    /// ```
+    pub fn new(reader: Reader, lease: Lease) -> Self {
+        Self { reader, chunk: Vec::new(), cursor: 0, _lease: lease }
+    }
+}
+
+impl Read for ReadStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.cursor == self.chunk.len() {
+            match self.reader.read().map_err(io::Error::other)? {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+
+            self.cursor = 0;
+        }
+
+        let size = buf.len().min(self.chunk.len() - self.cursor);
+        buf[..size].copy_from_slice(&self.chunk[self.cursor..self.cursor + size]);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+/// 打开的读取流
+///
+/// `open` 每个头部分片上还没有关闭的读取流数量  
+/// `stale` 已经被替换，等待读取流全部关闭之后删除的旧链表
+#[derive(Default)]
+pub struct Readers {
+    open: HashMap<Head, usize>,
+    stale: HashSet<Head>,
+}
+
+impl Readers {
+    /// 推迟删除链表
+    ///
+    /// 链表上还有打开的读取流时记录下来并返回`true`，
+    /// 否则返回`false`，由调用方立即删除
+    pub fn defer(&mut self, head: Head) -> bool {
+        match self.open.contains_key(&head) {
+            true => self.stale.insert(head),
+            false => false,
         }
+    }
+}
+
+/// 读取租约
+///
+/// 读取流持有租约期间链表不会被`swap`删除，
+/// 最后一个租约释放时删除等待中的旧链表，
+/// 释放时无法返回错误，删除失败的分片保持原样
+pub struct Lease {
+    readers: Rc<RefCell<Readers>>,
+    tracks: Tracks,
+    head: Head,
+}
+
+impl Lease {
+    /// 打开租约
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Lease, Readers};
+    /// use std::collections::HashMap;
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let readers = Rc::new(RefCell::new(Readers::default()));
+    /// let lease = Lease::new(readers, Rc::new(RefCell::new(HashMap::new())), (1, 40));
+    /// ```
+    pub fn new(readers: Rc<RefCell<Readers>>, tracks: Tracks, head: Head) -> Self {
+        *readers.borrow_mut().open.entry(head).or_insert(0) += 1;
+        Self { readers, tracks, head }
+    }
+}
 
-        // 检查是否抵达轨道尾部
-        // 如果抵达尾部则前进到下个轨道
-        self.track_index += 1;
-        if self.track_index >= list.len() {
-            self.track_index = 0;
-            self.track_id += 1;
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut readers = self.readers.borrow_mut();
+        let count = readers.open.get_mut(&self.head).unwrap();
+        *count -= 1;
+        if *count > 0 {
+            return;
         }
 
-        Ok(Some(
-            chunk.to_vec()
-        ))
+        readers.open.remove(&self.head);
+        if readers.stale.remove(&self.head) {
+            if let Ok(mut tracks) = self.tracks.try_borrow_mut() {
+                let _ = remove_chain(&mut tracks, self.head.0, self.head.1);
+            }
+        }
     }
 }
+
+/// 检查错误是否为分片丢失或者损坏
+fn is_lost(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<KernelError>(),
+        Some(KernelError::MissingTrack(_))
+            | Some(KernelError::DanglingPointer(_, _))
+            | Some(KernelError::InvalidSize(_, _))
+            | Some(KernelError::ShortRead(_, _))
+    )
+}
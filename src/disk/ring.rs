@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::io::{self, Read, Write};
+
+/// 环形缓冲区
+///
+/// 容量固定的字节缓冲区，
+/// 克隆之后共享同一个缓冲区，
+/// 一端在读取线程写入，另一端在消费线程读取，
+/// 缓冲区满时写入阻塞，缓冲区空时读取阻塞，
+/// 从而由消费方控制读取速度.
+///
+/// 写入方完成之后调用`close`，
+/// 读取方读完剩余数据之后返回0，
+/// 写入方失败时调用`poison`，
+/// 读取方读完剩余数据之后返回错误而不是0，
+/// 读取方提前调用`close`时写入返回`BrokenPipe`.
+#[derive(Clone)]
+pub struct RingBuffer {
+    inner: Arc<(Mutex<Ring>, Condvar)>,
+    capacity: usize,
+}
+
+struct Ring {
+    buffer: VecDeque<u8>,
+    error: Option<String>,
+    closed: bool,
+}
+
+impl RingBuffer {
+    /// 创建环形缓冲区
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::RingBuffer;
+    ///
+    /// let ring = RingBuffer::new(4096 * 4);
+    /// ```
+    pub fn new(capacity: usize) -> Self {
+        let ring = Ring {
+            buffer: VecDeque::with_capacity(capacity),
+            error: None,
+            closed: false,
+        };
+
+        Self {
+            inner: Arc::new((Mutex::new(ring), Condvar::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// 关闭缓冲区
+    ///
+    /// 唤醒所有等待的读取和写入
+    pub fn close(&self) {
+        let (ring, signal) = &*self.inner;
+        ring.lock().unwrap().closed = true;
+        signal.notify_all();
+    }
+
+    /// 标记写入方失败并关闭缓冲区
+    ///
+    /// 读取方读完剩余数据之后返回包含`reason`的错误
+    pub fn poison(&self, reason: String) {
+        let (ring, signal) = &*self.inner;
+        let mut ring = ring.lock().unwrap();
+        ring.error = Some(reason);
+        ring.closed = true;
+        signal.notify_all();
+    }
+}
+
+impl Write for RingBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let (ring, signal) = &*self.inner;
+        let mut ring = ring.lock().unwrap();
+        while !ring.closed && ring.buffer.len() >= self.capacity {
+            ring = signal.wait(ring).unwrap();
+        }
+
+        if ring.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let size = data.len().min(self.capacity - ring.buffer.len());
+        ring.buffer.extend(&data[..size]);
+        signal.notify_all();
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for RingBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let (ring, signal) = &*self.inner;
+        let mut ring = ring.lock().unwrap();
+        while !ring.closed && ring.buffer.is_empty() {
+            ring = signal.wait(ring).unwrap();
+        }
+
+        if ring.buffer.is_empty() {
+            if let Some(reason) = &ring.error {
+                return Err(io::Error::other(reason.clone()));
+            }
+        }
+
+        let size = buf.len().min(ring.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(ring.buffer.drain(..size)) {
+            *dst = src;
+        }
+
+        signal.notify_all();
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+    use std::io::{ErrorKind, Read, Write};
+    use std::thread;
+
+    #[test]
+    fn write_is_bounded_by_capacity() {
+        let mut ring = RingBuffer::new(8);
+        assert_eq!(ring.write(&[1u8; 20]).unwrap(), 8);
+        let mut consumer = ring.clone();
+        let producer = thread::spawn(move || {
+            ring.write_all(&[2u8; 12]).unwrap();
+            ring.close();
+        });
+
+        let mut out = Vec::new();
+        consumer.read_to_end(&mut out).unwrap();
+        producer.join().unwrap();
+        assert_eq!(out, [[1u8; 8].to_vec(), [2u8; 12].to_vec()].concat());
+    }
+
+    #[test]
+    fn poison_is_reported_after_remaining_data() {
+        let mut ring = RingBuffer::new(16);
+        let mut consumer = ring.clone();
+        ring.write_all(b"partial").unwrap();
+        ring.poison("missing track".to_string());
+
+        let mut buffer = [0u8; 16];
+        assert_eq!(consumer.read(&mut buffer).unwrap(), 7);
+        assert_eq!(&buffer[..7], b"partial");
+        let error = consumer.read(&mut buffer).unwrap_err();
+        assert_eq!(error.to_string(), "missing track");
+    }
+
+    #[test]
+    fn closed_reader_breaks_writer() {
+        let mut ring = RingBuffer::new(4);
+        let consumer = ring.clone();
+        ring.write_all(&[0u8; 4]).unwrap();
+        let producer = thread::spawn(move || ring.write(&[0u8; 4]));
+        consumer.close();
+        assert_eq!(producer.join().unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
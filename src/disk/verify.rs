@@ -0,0 +1,141 @@
+use super::{Codec, Fs, KernelError, HEADER_SIZE, TRACK_HEADER_SIZE};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use std::path::Path;
+use std::sync::Mutex;
+use anyhow::Result;
+
+/// 校验报告
+///
+/// `chunks` 校验的有效分片数量  
+/// `errors` 发现的损坏分片，按照轨道和位置排序
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub chunks: u64,
+    pub errors: Vec<(u16, u64, KernelError)>,
+}
+
+/// 轨道校验任务
+///
+/// `free` 轨道的失效分片，失效分片不做校验  
+/// `sizes` 所有轨道的文件长度，用于检查跨轨道的链接
+pub struct Task<'a> {
+    pub id: u16,
+    pub free: HashSet<u64>,
+    pub sizes: &'a HashMap<u16, u64>,
+    pub chunk_size: u64,
+    pub codec: Codec,
+}
+
+/// 读取限速
+///
+/// 所有校验线程共享，
+/// 限制每秒读取的分片数量
+pub struct Limiter {
+    rate: Option<u64>,
+    state: Mutex<(Instant, u64)>,
+}
+
+impl VerifyReport {
+    /// 合并报告
+    pub fn merge(&mut self, other: VerifyReport) {
+        self.chunks += other.chunks;
+        self.errors.extend(other.errors);
+        self.errors.sort_unstable_by_key(|(id, offset, _)| (*id, *offset));
+    }
+}
+
+impl Limiter {
+    /// 创建限速器
+    ///
+    /// `rate`为空时不限速
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new((Instant::now(), 0)),
+            rate,
+        }
+    }
+
+    /// 申请读取一个分片
+    ///
+    /// 超过限速时等待
+    pub fn acquire(&self) {
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return,
+        };
+
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state.1 += 1;
+            state.0 + Duration::from_secs_f64(state.1 as f64 / rate as f64)
+        };
+
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+}
+
+impl Task<'_> {
+    /// 校验轨道
+    ///
+    /// 使用独立的文件句柄读取轨道，
+    /// 所以可以在多个线程中同时校验不同的轨道，
+    /// 检查有效分片的长度和下个分片的位置
+    #[rustfmt::skip]
+    pub fn run(self, path: &Path, limiter: &Limiter) -> Result<VerifyReport> {
+        let mut file = Fs::new(path.join(format!("{}.track", self.id)))?;
+        let size = self.sizes.get(&self.id).copied().unwrap_or(0);
+        let mut buffer = [0u8; HEADER_SIZE];
+        let mut report = VerifyReport::default();
+        let diff_size = self.chunk_size as usize - HEADER_SIZE;
+
+        let mut offset = TRACK_HEADER_SIZE;
+        while offset + self.chunk_size <= size {
+            if self.free.contains(&offset) {
+                offset += self.chunk_size;
+                continue;
+            }
+
+            limiter.acquire();
+            file.intact_read(&mut buffer, offset)?;
+            let header = self.codec.header(&buffer);
+            report.chunks += 1;
+
+            if header.size > diff_size {
+                report.errors.push((self.id, offset, KernelError::InvalidSize(self.id, offset)));
+            } else if let Some((track, next)) = header.next {
+                if let Err(e) = self.check(track, next) {
+                    report.errors.push((self.id, offset, e));
+                }
+            }
+
+            offset += self.chunk_size;
+        }
+
+        Ok(report)
+    }
+
+    /// 检查下个分片的位置
+    fn check(&self, track: u16, next: u64) -> std::result::Result<(), KernelError> {
+        let size = match self.sizes.get(&track) {
+            Some(size) => *size,
+            None => return Err(KernelError::MissingTrack(track)),
+        };
+
+        if next < TRACK_HEADER_SIZE {
+            return Err(KernelError::CorruptChain(next));
+        }
+
+        if !(next - TRACK_HEADER_SIZE).is_multiple_of(self.chunk_size) {
+            return Err(KernelError::UnalignedOffset(next));
+        }
+
+        match next + self.chunk_size > size {
+            true => Err(KernelError::DanglingPointer(track, next)),
+            false => Ok(()),
+        }
+    }
+}
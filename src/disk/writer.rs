@@ -1,10 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use bytes::BytesMut;
 use anyhow::Result;
 use std::rc::Rc;
 use super::{
+    KernelError,
     KernelOptions,
-    AllocMap,
+    HEADER_SIZE,
     Tracks
 };
 
@@ -15,6 +16,18 @@ pub enum Callback {
     Done,
 }
 
+/// 写入循环状态
+///
+/// `Filling` 从外部流读取数据并写入  
+/// `Finalizing` 外部流读取到0，不再读取，写入剩余数据直到完成  
+/// `Done` 写入完成
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Filling,
+    Finalizing,
+    Done,
+}
+
 /// 链表上个节点
 ///
 /// 因为链表的特性导致写入需要延迟，
@@ -22,6 +35,7 @@ pub enum Callback {
 pub struct Previous {
     track: u16,
     index: u64,
+    head: bool,
     data: BytesMut,
 }
 
@@ -29,13 +43,27 @@ pub struct Previous {
 ///
 /// 写入数据到轨道中，
 /// 内部维护游标和写入策略
+///
+/// #### limit
+/// 数据最大长度，由`max_object_size`决定，
+/// 禁止跨轨道时不超过单个轨道可以保存的数据长度，
+/// 超过时立即返回`KernelError::ObjectTooLarge`
+///
+/// #### reserved
+/// 预先分配的分片位置，
+/// 写入时优先使用，写入结束之后释放剩余的分片
 pub struct Writer {
-    pub alloc_map: AllocMap,
-    index: HashMap<u16, usize>,
+    pub layout: Option<Vec<(u16, u64)>>,
+    pub head: Option<(u16, u64)>,
+    affected: HashSet<u16>,
     previous: Option<Previous>,
+    reserved: VecDeque<(u16, u64)>,
+    limit: Option<u64>,
     buffer: BytesMut,
     diff_size: usize,
     tracks: Tracks,
+    written: u64,
+    spill: bool,
     track: u16
 }
 
@@ -57,12 +85,25 @@ impl Writer {
     /// let writer = Writer::new(&mut tracks, options);
     /// ```
     pub fn new(tracks: Tracks, options: Rc<KernelOptions>) -> Self {
+        let limit = match options.spill {
+            false => Some(options.track_capacity()),
+            true => None,
+        };
+
         Self {
-            diff_size: (options.chunk_size - 10) as usize,
+            limit: match (options.max_object_size, limit) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+            diff_size: options.chunk_size as usize - HEADER_SIZE,
+            affected: HashSet::new(),
             buffer: BytesMut::new(),
-            alloc_map: Vec::new(),
-            index: HashMap::new(),
+            reserved: VecDeque::new(),
+            spill: options.spill,
+            layout: None,
+            head: None,
             previous: None,
+            written: 0,
             track: 1,
             tracks,
         }
@@ -96,6 +137,64 @@ impl Writer {
         }
     }
 
+    /// 预先分配分片
+    ///
+    /// 在当前轨道中连续分配最多`chunks`个分片，
+    /// 轨道空间不足时只分配能够分配的部分
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Writer, KernelOptions};
+    /// use std::collections::HashMap;
+    /// use std::rc::Rc;
+    /// 
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut tracks = HashMap::new();
+    /// let mut writer = Writer::new(&mut tracks, options);
+    /// writer.reserve(16).unwrap();
+    /// ```
+    pub fn reserve(&mut self, chunks: usize) -> Result<()> {
+        let mut tracks = self.tracks.borrow_mut();
+        let track = match tracks.get_mut(&self.track) {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+
+        while self.reserved.len() < chunks {
+            match track.alloc()? {
+                Some(index) => self.reserved.push_back((self.track, index)),
+                None => break,
+            }
+        }
+
+        if !self.reserved.is_empty() {
+            self.affected.insert(self.track);
+        }
+
+        Ok(())
+    }
+
+    /// 已经写入的数据长度
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// 使用给定的预分配分片
+    ///
+    /// 批量写入时由外部统一分配分片，
+    /// 然后按照数据分配给每个写入流
+    pub fn assign(&mut self, chunks: impl IntoIterator<Item = (u16, u64)>) {
+        for (track, index) in chunks {
+            self.affected.insert(track);
+            self.reserved.push_back((track, index));
+        }
+    }
+
     /// 写入结束
     ///
     /// 当没有数据写入的时候，
@@ -105,9 +204,12 @@ impl Writer {
     fn done(&mut self) -> Result<Option<Callback>> {
         
         // 检查是否有未处理的数据
-        // 如果存在未处理数据则将数据全部写入
-        if self.buffer.len() > 0 {
-            self.write_buffer(&[], true)?;
+        // 如果存在未处理数据则将数据全部写入，
+        // 写入过程中需要创建轨道时交给上级处理
+        if self.buffer.len() > 0 || self.head.is_none() {
+            if let Some(callback) = self.write_buffer(&[], true)? {
+                return Ok(Some(callback));
+            }
         }
 
         // 检查是否有未处理的节点
@@ -115,12 +217,16 @@ impl Writer {
         let mut tracks = self.tracks.borrow_mut();
         if let Some(previous) = self.previous.as_ref() {
             let track = tracks.get_mut(&previous.track).unwrap();
-            track.write(None, &previous.data, previous.index)?;
+            track.write(None, previous.head, &previous.data, previous.index)?;
         }
 
-        // 遍历所有受影响的轨道
+        // 释放没有使用的预分配分片，
+        // 然后遍历所有受影响的轨道
         // 为每个轨道保存状态
-        for (track_id, _) in self.index.iter() {
+        drop(tracks);
+        self.free_reserved()?;
+        let mut tracks = self.tracks.borrow_mut();
+        for track_id in self.affected.iter() {
             tracks.get_mut(track_id).unwrap().flush()?;
         }
 
@@ -129,11 +235,51 @@ impl Writer {
         ))
     }
 
+    /// 中止写入
+    ///
+    /// 结束已经写入的分片链表并保存轨道状态，
+    /// 返回已经写入的头部分片，
+    /// 由上级删除已经写入的分片
+    pub fn abort(&mut self) -> Result<Option<(u16, u64)>> {
+        self.free_reserved()?;
+        let mut tracks = self.tracks.borrow_mut();
+        if let Some(previous) = self.previous.take() {
+            let track = tracks.get_mut(&previous.track).unwrap();
+            track.write(None, previous.head, &previous.data, previous.index)?;
+        }
+
+        for track_id in self.affected.iter() {
+            tracks.get_mut(track_id).unwrap().flush()?;
+        }
+
+        Ok(self.head.take())
+    }
+
+    /// 释放没有使用的预分配分片
+    fn free_reserved(&mut self) -> Result<()> {
+        let mut groups: HashMap<u16, Vec<u64>> = HashMap::new();
+        for (track, index) in self.reserved.drain(..) {
+            groups.entry(track).or_default().push(index);
+        }
+
+        let mut tracks = self.tracks.borrow_mut();
+        for (track, offsets) in groups {
+            tracks.get_mut(&track).unwrap().free(offsets)?;
+        }
+
+        Ok(())
+    }
+
     /// 分配写入轨道
     ///
     /// 为内部分配合理的轨道游标
     #[rustfmt::skip]
     fn alloc(&mut self) -> Result<Callback> {
+        if let Some((track, index)) = self.reserved.pop_front() {
+            self.track = track;
+            return Ok(Callback::Index(index));
+        }
+
         let mut tracks = self.tracks.borrow_mut();
         
         // 无限循环
@@ -147,10 +293,13 @@ impl Writer {
         }
 
         // 检查轨道大小是否可以写入分片
-        // 如果可以则跳出，否则递加到下个轨道
+        // 如果可以则跳出，否则递加到下个轨道，
+        // 禁止跨轨道时数据写入之后不能切换轨道
         let track = tracks.get_mut(&self.track).unwrap();
         if let Some(index) = track.alloc()? {
             return Ok(Callback::Index(index));
+        } else if !self.spill && self.head.is_some() {
+            return Err(KernelError::SpillDisabled(self.track).into());
         } else {
             self.track += 1;
             continue;
@@ -163,6 +312,13 @@ impl Writer {
     /// 将数据自动分配到有空间写入的轨道上
     #[rustfmt::skip]
     fn write_buffer(&mut self, chunk: &[u8], free: bool) -> Result<Option<Callback>> {
+        self.written += chunk.len() as u64;
+        if let Some(limit) = self.limit {
+            if self.written > limit {
+                return Err(KernelError::ObjectTooLarge(limit).into());
+            }
+        }
+
         self.buffer.extend_from_slice(chunk);
         let diff_size = self.diff_size;
 
@@ -170,9 +326,10 @@ impl Writer {
         // 直到无法继续分配
     loop {
         
-        // 缓冲区为空直接跳出
+        // 缓冲区为空直接跳出，
+        // 空数据清空时也需要写入一个头部分片
         let buffer_size = self.buffer.len();
-        if buffer_size == 0 {
+        if buffer_size == 0 && !(free && self.head.is_none()) {
             break;
         }
 
@@ -191,11 +348,18 @@ impl Writer {
             return Ok(Some(alloc_result))
         }
 
-        // 为了避免不必要的重复写入
-        // 所以这里先检查轨道索引是否存在
-        if !self.index.contains_key(&self.track) {
-            self.index.insert(self.track, self.alloc_map.len());
-            self.alloc_map.push((self.track, Vec::new()));
+        // 记录受影响的轨道
+        // 第一个分配的分片为头部分片
+        self.affected.insert(self.track);
+        let head = self.head.is_none();
+        if head {
+            self.head = Some((self.track, index));
+        }
+
+        // 如果需要记录分片分布
+        // 则按照分配顺序记录
+        if let Some(layout) = self.layout.as_mut() {
+            layout.push((self.track, index));
         }
 
         // 如果存在节点缓存
@@ -203,7 +367,8 @@ impl Writer {
         if let Some(previous) = self.previous.as_ref() {
             let mut tracks = self.tracks.borrow_mut();
             let track = tracks.get_mut(&previous.track).unwrap();
-            track.write(Some(index), &previous.data, previous.index)?;
+            let next = Some((self.track, index));
+            track.write(next, previous.head, &previous.data, previous.index)?;
         }
 
         // 如果缓冲区大小比分配长度小
@@ -218,15 +383,9 @@ impl Writer {
         self.previous = Some(Previous {
             data: self.buffer.split_to(off_index),
             track: self.track,
-            index
+            index,
+            head,
         });
-
-        // 将节点索引写入分配表
-        if let Some(offset) = self.index.get(&self.track) {
-            if let Some((_, list)) = self.alloc_map.get_mut(*offset) {
-                list.push(index);
-            }
-        }
     }
 
         Ok(None)
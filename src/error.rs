@@ -0,0 +1,81 @@
+use std::fmt;
+
+/// 核心错误
+///
+/// 可以通过`anyhow::Error::downcast_ref`
+/// 从返回的错误中取出具体的错误类型
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelError {
+    /// 分片已经被复用，
+    /// 引用的代数和分片当前代数不一致
+    StaleHandle,
+    /// 分片引用的轨道不存在
+    MissingTrack(u16),
+    /// 分片引用的位置超出轨道范围
+    DanglingPointer(u16, u64),
+    /// 分片位置没有和分片大小对齐
+    UnalignedOffset(u64),
+    /// 分片链表指向轨道头区域，
+    /// 轨道头区域不会存放任何分片
+    CorruptChain(u64),
+    /// 写入之后读回的分片内容不一致
+    WriteMismatch(u16, u64),
+    /// 分片数据长度超出分片范围
+    InvalidSize(u16, u64),
+    /// 数据超过允许的最大长度
+    ObjectTooLarge(u64),
+    /// 禁止跨轨道时轨道剩余空间不足以写入数据
+    SpillDisabled(u16),
+    /// 读取的分片不完整
+    ShortRead(u16, u64),
+    /// 副本和主存储的结果不一致
+    ReplicaDiverged,
+    /// 配置无效
+    InvalidOptions(&'static str),
+    /// 数据ID不存在或者没有预留
+    InvalidId(u64),
+    /// 分片标记包含当前版本不认识的位
+    UnknownFlags(u64),
+    /// 头部分片不是有效数据的头部，数据已经被删除
+    NotFound(u16, u64),
+    /// 存储目录已经被其他实例锁定
+    AlreadyLocked,
+    /// 写入超出存储配额
+    QuotaExceeded(u64),
+    /// 轨道文件头不完整，
+    /// 或者不是当前格式版本的轨道文件
+    InvalidTrack(u16),
+    /// 索引项长度不正确
+    CorruptIndex,
+    /// 存储还没有初始化
+    NotInitialized,
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::StaleHandle => write!(f, "stale handle"),
+            Self::MissingTrack(id) => write!(f, "missing track: {}", id),
+            Self::DanglingPointer(id, offset) => write!(f, "dangling pointer: track {} offset {}", id, offset),
+            Self::UnalignedOffset(offset) => write!(f, "unaligned offset: {}", offset),
+            Self::CorruptChain(offset) => write!(f, "corrupt chain: offset {}", offset),
+            Self::WriteMismatch(id, offset) => write!(f, "write mismatch: track {} offset {}", id, offset),
+            Self::InvalidSize(id, offset) => write!(f, "invalid chunk size: track {} offset {}", id, offset),
+            Self::ObjectTooLarge(limit) => write!(f, "object too large: limit {}", limit),
+            Self::SpillDisabled(id) => write!(f, "spill disabled: track {} is full", id),
+            Self::ShortRead(id, offset) => write!(f, "short read: track {} offset {}", id, offset),
+            Self::ReplicaDiverged => write!(f, "replica diverged"),
+            Self::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            Self::InvalidId(id) => write!(f, "invalid object id: {}", id),
+            Self::UnknownFlags(offset) => write!(f, "unknown chunk flags: offset {}", offset),
+            Self::NotFound(id, offset) => write!(f, "not found: track {} offset {}", id, offset),
+            Self::AlreadyLocked => write!(f, "directory already locked"),
+            Self::QuotaExceeded(limit) => write!(f, "quota exceeded: limit {}", limit),
+            Self::InvalidTrack(id) => write!(f, "invalid track header: track {}", id),
+            Self::CorruptIndex => write!(f, "corrupt index entry"),
+            Self::NotInitialized => write!(f, "disk is not initialized"),
+        }
+    }
+}
+
+impl std::error::Error for KernelError {}
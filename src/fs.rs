@@ -45,6 +45,31 @@ impl Fs {
         Ok(Self { cursor: 0, file })
     }
 
+    /// 独占创建文件类
+    ///
+    /// 只在文件不存在时创建文件，
+    /// 文件已经存在时返回空
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Fs;
+    ///
+    /// let fs = Fs::create_new("./a.text").unwrap();
+    /// ```
+    pub fn create_new<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path);
+        match file {
+            Ok(file) => Ok(Some(Self { cursor: 0, file })),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     /// 获取文件信息
     ///
     /// # Examples
@@ -74,7 +99,8 @@ impl Fs {
     /// ```
     pub fn write(&mut self, chunk: &[u8], offset: u64) -> Result<()> {
         self.seek(offset)?;
-        self.file.write_all(chunk)?;
+        let result = self.file.write_all(chunk);
+        self.check(result)?;
         self.cursor_next(chunk.len());
         Ok(())
     }
@@ -117,7 +143,8 @@ impl Fs {
     /// ```
     pub fn read(&mut self, chunk: &mut [u8], offset: u64) -> Result<usize> {
         self.seek(offset)?;
-        let size = self.file.read(chunk)?;
+        let result = self.file.read(chunk);
+        let size = self.check(result)?;
         self.cursor_next(size);
         Ok(size)
     }
@@ -140,26 +167,78 @@ impl Fs {
     /// ```
     pub fn intact_read(&mut self, chunk: &mut [u8], offset: u64) -> Result<()> {
         self.seek(offset)?;
-        self.file.read_exact(chunk)?;
+        let result = self.file.read_exact(chunk);
+        self.check(result)?;
         self.cursor_next(chunk.len());
         Ok(())
     }
 
+    /// 截断文件
+    ///
+    /// 将文件长度设置为指定长度
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Fs;
+    /// use std::path::Path;
+    ///
+    /// let mut fs = Fs::new("./a.text").unwrap();
+    /// fs.truncate(0).unwrap();
+    /// ```
+    pub fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file.set_len(size)?;
+        Ok(())
+    }
+
     /// 设置内部游标
     #[rustfmt::skip]
     fn seek(&mut self, offset: u64) -> Result<()> {
         if offset == self.cursor { return Ok(()) }
-        self.file.seek(SeekFrom::Start(offset))?;
+        let result = self.file.seek(SeekFrom::Start(offset));
+        self.check(result)?;
         self.cursor = offset;
         Ok(())
     }
 
+    /// 检查读写结果
+    ///
+    /// 出错时文件实际位置未知，
+    /// 使内部游标失效，
+    /// 下次读写强制重新定位
+    fn check<T>(&mut self, result: std::io::Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.cursor = u64::MAX;
+        }
+
+        Ok(result?)
+    }
+
     /// 推进内部游标
     fn cursor_next(&mut self, size: usize) {
         self.cursor += size as u64;
     }
 }
 
+/// 锁定目录
+///
+/// 对目录加独占的建议锁，
+/// 锁被其他句柄持有时阻塞等待，
+/// 返回的句柄关闭时释放锁
+///
+/// # Examples
+///
+/// ```no_run
+/// use super::lock_dir;
+///
+/// let guard = lock_dir("./.static").unwrap();
+/// ```
+pub fn lock_dir<P: AsRef<Path>>(path: P) -> Result<File> {
+    let dir = File::open(path)?;
+    dir.lock()?;
+    Ok(dir)
+}
+
 /// 读取目录
 ///
 /// # Examples
@@ -173,3 +252,24 @@ impl Fs {
 pub fn readdir<P: AsRef<Path>>(path: P) -> Result<ReadDir> {
     Ok(read_dir(path)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Fs;
+
+    #[test]
+    fn failed_read_invalidates_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.text");
+        std::fs::write(&path, b"abcdefgh").unwrap();
+        let mut fs = Fs::new(&path).unwrap();
+
+        // 读取失败之后文件实际位置已经移动，
+        // 再次读取同一个位置时必须重新定位
+        let mut buffer = [0u8; 16];
+        assert!(fs.intact_read(&mut buffer, 4).is_err());
+        let mut buffer = [0u8; 4];
+        fs.intact_read(&mut buffer, 4).unwrap();
+        assert_eq!(&buffer, b"efgh");
+    }
+}
@@ -1,21 +1,19 @@
-use super::KernelOptions;
+use super::{KernelError, KernelOptions};
 use std::path::Path;
 use anyhow::Result;
-use rocksdb::DB;
+use rocksdb::{DB, IteratorMode};
 use bytes::{
     Buf, 
     BufMut, 
     BytesMut
 };
 
-/// 分配表
-pub type AllocMap = Vec<(u16, Vec<u64>)>;
-
 /// 索引
 ///
 /// 索引构筑在RocksDB上，
 /// 这里抽象出标准接口来
-/// 操作索引存储
+/// 操作索引存储，
+/// 索引保存数据头部分片所在的轨道和位置
 pub struct Index(DB);
 
 impl Index {
@@ -46,7 +44,6 @@ impl Index {
     ///
     /// ```no_run
     /// use super::{Index, KernelOptions};
-    /// use std::collections::HashMap;
     /// use std::rc::Rc;
     ///
     /// let options = Rc::new(KernelOptions::from(
@@ -56,10 +53,7 @@ impl Index {
     ///
     /// let mut index = Index::new(options).unwrap();
     ///
-    /// let mut alloc_map = HashMap::new();
-    /// alloc_map.insert(1, vec![1, 2, 3]);
-    ///
-    /// index.set(b"a", &alloc_map).unwrap();
+    /// index.set(b"a", (1, 40)).unwrap();
     /// assert_eq!(index.has(b"a"), true);
     /// ```
     pub fn has(&self, key: &[u8]) -> Result<bool> {
@@ -72,7 +66,6 @@ impl Index {
     ///
     /// ```no_run
     /// use super::{Index, KernelOptions};
-    /// use std::collections::HashMap;
     /// use std::rc::Rc;
     ///
     /// let options = Rc::new(KernelOptions::from(
@@ -82,10 +75,7 @@ impl Index {
     ///
     /// let mut index = Index::new(options).unwrap();
     ///
-    /// let mut alloc_map = HashMap::new();
-    /// alloc_map.insert(1, vec![1, 2, 3]);
-    ///
-    /// index.set(b"a", &alloc_map).unwrap();
+    /// index.set(b"a", (1, 40)).unwrap();
     /// assert_eq!(index.has(b"a").unwrap(), true);
     ///
     /// index.remove(b"a").unwrap();
@@ -102,7 +92,6 @@ impl Index {
     ///
     /// ```no_run
     /// use super::{Index, KernelOptions};
-    /// use std::collections::HashMap;
     /// use std::rc::Rc;
     ///
     /// let options = Rc::new(KernelOptions::from(
@@ -112,23 +101,13 @@ impl Index {
     ///
     /// let mut index = Index::new(options).unwrap();
     ///
-    /// let mut alloc_map = HashMap::new();
-    /// alloc_map.insert(1, vec![1, 2, 3]);
-    ///
-    /// index.set(b"a", &alloc_map).unwrap();
-    ///
-    /// if let Some(value) = index.get(b"test").unwrap().get_mut(&1) {
-    ///     assert_eq!(value.next(), Some(1));
-    ///     assert_eq!(value.next(), Some(2));
-    ///     assert_eq!(value.next(), Some(3));
-    ///     assert_eq!(value.next(), None);
-    /// }
-    ///
+    /// index.set(b"a", (1, 40)).unwrap();
+    /// assert_eq!(index.get(b"a").unwrap(), Some((1, 40)));
     /// ```
     #[rustfmt::skip]
-    pub fn get(&self, key: &[u8]) -> Result<Option<AllocMap>> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<(u16, u64)>> {
         Ok(match self.0.get_pinned(key)? {
-            Some(x) => Some(decoder(x.as_ref())), 
+            Some(x) => Some(decoder(x.as_ref())?), 
             None => None
         })
     }
@@ -139,7 +118,6 @@ impl Index {
     ///
     /// ```no_run
     /// use super::{Index, KernelOptions};
-    /// use std::collections::HashMap;
     /// use std::rc::Rc;
     ///
     /// let options = Rc::new(KernelOptions::from(
@@ -149,71 +127,96 @@ impl Index {
     ///
     /// let mut index = Index::new(options).unwrap();
     ///
-    /// let mut alloc_map = HashMap::new();
-    /// alloc_map.insert(1, vec![1, 2, 3]);
-    ///
-    /// index.set(b"a", &alloc_map).unwrap();
+    /// index.set(b"a", (1, 40)).unwrap();
     /// assert_eq!(index.has(b"a").unwrap(), true);
     /// ```
-    pub fn set(&mut self, key: &[u8], value: &AllocMap) -> Result<()> {
+    pub fn set(&mut self, key: &[u8], value: (u16, u64)) -> Result<()> {
         self.0.put(key, &encoder(value)[..])?;
         Ok(())
     }
+
+    /// 获取全部键
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Index, KernelOptions};
+    ///
+    /// let options = KernelOptions::from(
+    ///     "./.static".to_string(),
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// let index = Index::new(&options).unwrap();
+    /// let keys = index.keys();
+    /// ```
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.0.iterator(IteratorMode::Start)
+            .map(|(key, _)| key.to_vec())
+            .collect()
+    }
+
+    /// 获取全部原始索引项
+    ///
+    /// 返回未解码的键和值，
+    /// 用于迁移旧版本的索引
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Index, KernelOptions};
+    ///
+    /// let options = KernelOptions::from(
+    ///     "./.static".to_string(),
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// let index = Index::new(&options).unwrap();
+    /// let entries = index.entries();
+    /// ```
+    pub fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.0.iterator(IteratorMode::Start)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect()
+    }
 }
 
 /// 解码索引
 ///
 /// 将索引缓冲区转为
-/// 可迭代的索引列表
-#[rustfmt::skip]
-fn decoder(mut chunk: &[u8]) -> AllocMap {
-    let mut result = Vec::new();
-
-    // 无限循环
-    // 迭代所有轨道
-loop {
-    if chunk.len() < 8 {
-        break;
+/// 头部分片所在的轨道和位置，
+/// 长度不正确时返回`KernelError::CorruptIndex`
+fn decoder(mut chunk: &[u8]) -> Result<(u16, u64)> {
+    if chunk.len() != 10 {
+        return Err(KernelError::CorruptIndex.into());
     }
 
-    // 轨道ID
-    // 索引列表长度
     let id = chunk.get_u16();
-    let item_size = chunk.get_u32() as usize;
-
-    // 索引列表真实长度
-    // 检查索引列表是否足够解码
-    if item_size * 8 > chunk.len() {
-        break;
-    }
-    
-    // 读取索引列表
-    let mut list = Vec::new();
-    for _ in 0..item_size {
-        list.push(chunk.get_u64());
-    }
-
-    result.push((
-        id, list
-    ));
-}
-
-    result
+    let index = chunk.get_u64();
+    Ok((id, index))
 }
 
 /// 编码索引
 ///
-/// 将索引分配表转为
-/// 字节缓冲区
-fn encoder(map: &AllocMap) -> BytesMut {
+/// 将头部分片所在的轨道和位置
+/// 转为字节缓冲区
+fn encoder((id, index): (u16, u64)) -> BytesMut {
     let mut packet = BytesMut::new();
-    for (id, value) in map {
-        packet.put_u16(*id);
-        packet.put_u32(value.len() as u32);
-        for index in value {
-            packet.put_u64(*index);
+    packet.put_u16(id);
+    packet.put_u64(index);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decoder, encoder, KernelError};
+
+    #[test]
+    fn decoder_checks_length() {
+        assert_eq!(decoder(&encoder((3, 4136))).unwrap(), (3, 4136));
+        for chunk in [&[0u8; 9][..], &[0u8; 11][..], &[][..]] {
+            let error = decoder(chunk).unwrap_err();
+            assert_eq!(error.downcast_ref::<KernelError>(), Some(&KernelError::CorruptIndex));
         }
     }
-
-    packet
-}
\ No newline at end of file
+}
@@ -0,0 +1,307 @@
+use super::{Disk, Index, KernelError, KernelOptions};
+use super::fs::{readdir, Fs};
+use super::track::TRACK_MAGIC;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
+use std::path::Path;
+use anyhow::Result;
+use bytes::Buf;
+
+/// 旧版本轨道文件后缀
+const LEGACY_SUFFIX: &str = ".track.v0";
+
+/// 旧版本轨道头部长度
+const LEGACY_HEADER_SIZE: u64 = 24;
+
+/// 旧版本分片头部长度
+const LEGACY_CHUNK_HEADER: usize = 10;
+
+/// 当前版本索引值长度
+const INDEX_SIZE: usize = 10;
+
+/// 隔离旧版本轨道
+///
+/// 版本0的轨道文件没有文件标识，
+/// 头部只有24字节的失效链表和数据长度，
+/// 这里将这些轨道重命名为`<id>.track.v0`，
+/// 初始化时不会再作为当前版本的轨道打开，
+/// 长度小于旧版本头部的文件保持原样
+///
+/// # Examples
+///
+/// ```no_run
+/// use super::legacy;
+///
+/// legacy::isolate("./.static").unwrap();
+/// ```
+pub fn isolate<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    for dir in readdir(path)? {
+        let name = match dir?.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        if !name.ends_with(".track") || name.replace(".track", "").parse::<u16>().is_err() {
+            continue;
+        }
+
+        let file = path.join(&name);
+        let mut fs = Fs::new(&file)?;
+        if fs.stat()?.len() < LEGACY_HEADER_SIZE {
+            continue;
+        }
+
+        let mut magic = [0u8; 4];
+        fs.intact_read(&mut magic, 0)?;
+        if magic[..] != TRACK_MAGIC[..] {
+            std::fs::rename(&file, path.join(format!("{}{}", name, ".v0")))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// 迁移旧版本数据
+///
+/// 索引中长度不是10字节的值为旧版本的分配表，
+/// 按照分配表顺序从旧版本轨道读取数据，
+/// 写入当前版本的轨道之后更新索引，
+/// 中途失败时已经迁移的索引已经是新格式，
+/// 重新打开时从剩余的索引继续，
+/// 全部迁移完成之后删除旧版本轨道
+///
+/// # Examples
+///
+/// ```no_run
+/// use super::{legacy, Disk, Index, KernelOptions};
+/// use std::rc::Rc;
+///
+/// let options = Rc::new(KernelOptions::from(
+///     "./.static".to_string(),
+///     1024 * 1024 * 1024 * 1
+/// ));
+///
+/// let mut disk = Disk::new(options.clone());
+/// disk.init().unwrap();
+/// let mut index = Index::new(&options).unwrap();
+/// legacy::migrate(&options, &mut disk, &mut index).unwrap();
+/// ```
+pub fn migrate(options: &KernelOptions, disk: &mut Disk, index: &mut Index) -> Result<()> {
+    let path: &Path = options.path.as_ref();
+    let mut tracks = HashMap::new();
+    for dir in readdir(path)? {
+        if let Ok(name) = dir?.file_name().into_string() {
+            if name.ends_with(LEGACY_SUFFIX) {
+                if let Ok(id) = name.replace(LEGACY_SUFFIX, "").parse::<u16>() {
+                    tracks.insert(id, Fs::new(path.join(&name))?);
+                }
+            }
+        }
+    }
+
+    if tracks.is_empty() {
+        return Ok(());
+    }
+
+    for (key, value) in index.entries() {
+        if value.len() == INDEX_SIZE {
+            continue;
+        }
+
+        let stream = LegacyStream {
+            chunks: decoder(&value)?,
+            chunk_size: options.chunk_size as usize,
+            tracks: &mut tracks,
+            chunk: Vec::new(),
+            cursor: 0,
+        };
+
+        let head = disk.write(stream)?;
+        index.set(&key, head)?;
+    }
+
+    let ids = tracks.keys().copied().collect::<Vec<u16>>();
+    drop(tracks);
+    for id in ids {
+        std::fs::remove_file(path.join(format!("{}{}", id, LEGACY_SUFFIX)))?;
+    }
+
+    Ok(())
+}
+
+/// 旧版本数据流
+///
+/// 按照分配表顺序逐个读取旧版本分片，
+/// 只在内存中保留当前分片的数据
+struct LegacyStream<'a> {
+    chunks: VecDeque<(u16, u64)>,
+    tracks: &'a mut HashMap<u16, Fs>,
+    chunk_size: usize,
+    chunk: Vec<u8>,
+    cursor: usize,
+}
+
+impl LegacyStream<'_> {
+    /// 读取下个分片
+    ///
+    /// 旧版本分片头部为下个分片位置和数据长度，
+    /// 数据长度为0表示分片已满
+    fn next_chunk(&mut self) -> Result<bool> {
+        let (id, offset) = match self.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => return Ok(false),
+        };
+
+        let track = self.tracks.get_mut(&id)
+            .ok_or(KernelError::MissingTrack(id))?;
+        let mut buffer = vec![0u8; self.chunk_size];
+        track.intact_read(&mut buffer, offset)?;
+
+        let size = match (&buffer[8..LEGACY_CHUNK_HEADER]).get_u16() as usize {
+            0 => self.chunk_size - LEGACY_CHUNK_HEADER,
+            size => size,
+        };
+
+        if LEGACY_CHUNK_HEADER + size > self.chunk_size {
+            return Err(KernelError::InvalidSize(id, offset).into());
+        }
+
+        buffer.truncate(LEGACY_CHUNK_HEADER + size);
+        buffer.drain(..LEGACY_CHUNK_HEADER);
+        self.chunk = buffer;
+        self.cursor = 0;
+        Ok(true)
+    }
+}
+
+impl Read for LegacyStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.cursor == self.chunk.len() {
+            if !self.next_chunk().map_err(io::Error::other)? {
+                return Ok(0);
+            }
+        }
+
+        let size = std::cmp::min(buf.len(), self.chunk.len() - self.cursor);
+        buf[..size].copy_from_slice(&self.chunk[self.cursor..self.cursor + size]);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+/// 解码旧版本分配表
+///
+/// 分配表为连续的轨道编号、分片数量和分片位置列表，
+/// 长度不正确时返回`KernelError::CorruptIndex`
+fn decoder(mut value: &[u8]) -> Result<VecDeque<(u16, u64)>> {
+    let mut chunks = VecDeque::new();
+    while !value.is_empty() {
+        if value.len() < 6 {
+            return Err(KernelError::CorruptIndex.into());
+        }
+
+        let id = value.get_u16();
+        let count = value.get_u32() as usize;
+        if value.len() < count * 8 {
+            return Err(KernelError::CorruptIndex.into());
+        }
+
+        for _ in 0..count {
+            chunks.push_back((id, value.get_u64()));
+        }
+    }
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Kernel, KernelOptions};
+    use bytes::BufMut;
+
+    /// 构造版本0的分片
+    fn legacy_chunk(next: u64, data: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::with_capacity(4096);
+        chunk.put_u64(next);
+        chunk.put_u16(if data.len() == 4086 { 0 } else { data.len() as u16 });
+        chunk.put_slice(data);
+        chunk.resize(4096, 0);
+        chunk
+    }
+
+    /// 构造版本0的轨道
+    fn legacy_track(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut track = Vec::new();
+        track.put_u64(0);
+        track.put_u64(0);
+        track.put_u64(24 + chunks.len() as u64 * 4096);
+        for chunk in chunks {
+            track.put_slice(chunk);
+        }
+
+        track
+    }
+
+    #[test]
+    fn legacy_tracks_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = || KernelOptions::from(dir.path().to_str().unwrap().to_string(), 1024 * 1024);
+        let large = (0..4086 * 2 + 100).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+        let small = b"hello".to_vec();
+
+        // 大数据跨越两个轨道，
+        // 小数据只有一个未满分片
+        let one = legacy_track(&[
+            legacy_chunk(24 + 4096, &large[..4086]),
+            legacy_chunk(0, &large[4086..4086 * 2]),
+            legacy_chunk(0, &small),
+        ]);
+
+        let two = legacy_track(&[
+            legacy_chunk(0, &large[4086 * 2..]),
+        ]);
+
+        std::fs::write(dir.path().join("1.track"), one).unwrap();
+        std::fs::write(dir.path().join("2.track"), two).unwrap();
+
+        let mut large_map = Vec::new();
+        large_map.put_u16(1);
+        large_map.put_u32(2);
+        large_map.put_u64(24);
+        large_map.put_u64(24 + 4096);
+        large_map.put_u16(2);
+        large_map.put_u32(1);
+        large_map.put_u64(24);
+
+        let mut small_map = Vec::new();
+        small_map.put_u16(1);
+        small_map.put_u32(1);
+        small_map.put_u64(24 + 4096 * 2);
+
+        {
+            let db = rocksdb::DB::open_default(dir.path().join("index")).unwrap();
+            db.put(b"large", &large_map).unwrap();
+            db.put(b"small", &small_map).unwrap();
+        }
+
+        let mut kernel = Kernel::with_options(options()).unwrap();
+        let mut out = Vec::new();
+        kernel.read(b"large", &mut out).unwrap();
+        assert_eq!(out, large);
+
+        let mut out = Vec::new();
+        kernel.read(b"small", &mut out).unwrap();
+        assert_eq!(out, small);
+
+        assert!(!dir.path().join("1.track.v0").exists());
+        assert!(!dir.path().join("2.track.v0").exists());
+        drop(kernel);
+
+        // 迁移完成之后重新打开不再迁移
+        let mut kernel = Kernel::with_options(options()).unwrap();
+        let mut out = Vec::new();
+        kernel.read(b"large", &mut out).unwrap();
+        assert_eq!(out, large);
+    }
+}
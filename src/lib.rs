@@ -1,4 +1,3 @@
-
 //! # Kernel
 //! 
 //! 
@@ -9,42 +8,123 @@
 //! 每个分片内部具有链表形式的下个分片位置以及当前分片内容长度，
 //! 这虽然会导致一些空间浪费，
 //! 但这是无法避免的.
+//! 没有文件标识的旧版本轨道和旧版本索引在打开时迁移到当前格式.
 //! 
 //! ```
 //!     
-//!         |-  track header -|                /------------------------------/
-//!         +-----------------+  +-----------------------------+       +----------------------+
-//!         | U64 | U64 | U64 |  | 4KB | 4KB | 4KB | 4KB | 4KB >       | U16 | U64 | * (data) >
-//!         +-----------------+  +-----------------------------+       +----------------------+
-//!             |     |     |-> data size                                  |     |-> next chunk offset
-//!             |     |-> free chunk list last offset                      |-> chunk data size (if full is 0)
-//!             |-> free chunk list first offset
+//!         |------------------ track header ------------------|                /------------------------------------------------------/
+//!         +--------------------------------------------------+  +-----------------------------+       +----------------------------------------------+
+//!         | 4B | U16 | U16 | U64 | U64 | U64 | U16 | * (6B)  |  | 4KB | 4KB | 4KB | 4KB | 4KB >       | U64 | U32 | U16 | U16 | U8 | U32 | U64 | * (data) >
+//!         +--------------------------------------------------+  +-----------------------------+       +----------------------------------------------+
+//!           |    |     |     |     |     |     |-> track state (closed)                                   |     |     |     |     |    |     |-> last read time (head only)
+//!           |    |     |     |     |     |-> data size                                                    |     |     |     |     |    |-> read count (head only)
+//!           |    |     |     |     |-> free chunk list last offset                                        |     |     |     |     |-> chunk flags (head, empty, free)
+//!           |    |     |     |-> free chunk list first offset                                             |     |     |     |-> chunk generation
+//!           |    |     |-> tail chunk generation                                                          |     |     |-> next chunk track
+//!           |    |-> format version                                                                       |     |-> chunk data size (if full is 0)
+//!           |-> magic (PHYT)                                                                              |-> next chunk offset
 //! ```
 //! 
 
+mod bitmap;
 mod chunk;
+mod cluster;
 mod disk;
+mod error;
 mod index;
+mod legacy;
 mod track;
 mod fs;
 
-use disk::Disk;
+pub use error::KernelError;
+pub use cluster::Cluster;
+pub use disk::{
+    Disk, 
+    ObjectStats, 
+    objects::ObjectId, 
+    quota::QuotaDecision, 
+    ring::RingBuffer, 
+    verify::VerifyReport
+};
 use index::Index;
 use anyhow::{anyhow, Result};
 use std::io::{Read, Write};
+use std::time::Duration;
 use std::rc::Rc;
 
 /// 核心配置
 ///
 /// `directory` 存储目录  
 /// `track_size` 轨道文件最大长度  
-/// `chunk_size` 分片最大长度
+/// `chunk_size` 分片最大长度  
+/// `idle_defrag` 空闲时自动整理失效链表  
+/// `access_stats` 在头部分片中记录读取统计  
+/// `verify_writes` 写入头部分片之后读回校验，会增加写入开销  
+/// `free_bitmap` 使用位图代替失效链表记录失效分片，位图保存在轨道旁边的`<id>.free`文件中  
+/// `bitmap_rebuild_budget` 初始化时重建位图的时间预算，剩余部分推迟到第一次需要位图时完成，为空时不做限制  
+/// `verify_rate` 校验数据时每秒最多读取的分片数量  
+/// `free_list_persistence` 删除数据时失效链表的保存策略  
+/// `compact_trigger` 按需压缩轨道的条件  
+/// `max_track_file_bytes` 轨道文件长度的硬性上限，和`track_size`取较小值  
+/// `max_object_size` 单个数据的最大长度  
+/// `spill` 是否允许数据跨轨道写入  
+/// `max_read_buffer` 单次读取缓冲区的最大长度，为空时不做限制  
+/// `read_buffer_fraction` 单次读取缓冲区占系统可用内存的最大比例，为空时不做限制  
+/// `strict_flags` 读取到不认识的分片标记时返回错误，否则忽略  
+/// `lock` 初始化时在存储目录中创建锁文件，防止多个实例同时打开  
+/// `force_lock` 锁文件已经存在并且持有锁的进程已经退出时接管
 pub struct KernelOptions {
+    pub idle_defrag: Option<IdleDefrag>,
+    pub access_stats: bool,
+    pub verify_writes: bool,
+    pub free_bitmap: bool,
+    pub bitmap_rebuild_budget: Option<Duration>,
+    pub verify_rate: Option<u64>,
+    pub free_list_persistence: FreeListPersistence,
+    pub compact_trigger: Option<CompactTrigger>,
+    pub max_track_file_bytes: Option<u64>,
+    pub max_object_size: Option<u64>,
+    pub spill: bool,
+    pub max_read_buffer: Option<u64>,
+    pub read_buffer_fraction: Option<f64>,
+    pub strict_flags: bool,
+    pub lock: bool,
+    pub force_lock: bool,
     pub track_size: u64,
     pub chunk_size: u64,
     pub path: String,
 }
 
+/// 空闲整理配置
+///
+/// `idle` 无读写删除操作多久之后视为空闲  
+/// `io_budget` 单次整理允许的分片读写次数
+pub struct IdleDefrag {
+    pub idle: Duration,
+    pub io_budget: u64,
+}
+
+/// 压缩条件
+///
+/// `free_ratio` 失效分片占轨道分片的比例  
+/// `free_count` 失效分片数量  
+/// 满足任意一个条件的轨道需要压缩
+pub struct CompactTrigger {
+    pub free_ratio: f64,
+    pub free_count: u64,
+}
+
+/// 失效链表保存策略
+///
+/// `Eager` 每次删除数据之后立即保存轨道头  
+/// `Lazy` 延迟到下次写入数据、调用`flush`或者关闭时保存，
+/// 期间异常退出只会导致被删除的分片无法回收，不会破坏数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeListPersistence {
+    Eager,
+    Lazy,
+}
+
 /// 存储核心
 pub struct Kernel {
     disk: Disk,
@@ -65,11 +145,31 @@ impl Kernel {
     /// ).unwrap();
     /// ```
     pub fn new(path: String, track_size: u64) -> Result<Self> {
-        let configure = Rc::new(KernelOptions::from(path, track_size));
+        Self::with_options(KernelOptions::from(path, track_size))
+    }
+
+    /// 使用配置创建实例
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Kernel, KernelOptions};
+    ///
+    /// let options = KernelOptions::from(
+    ///     "./.static".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// let mut kernel = Kernel::with_options(options).unwrap();
+    /// ```
+    pub fn with_options(options: KernelOptions) -> Result<Self> {
+        let configure = Rc::new(options);
         let mut disk = Disk::new(configure.clone());
         disk.init()?;
+        let mut index = Index::new(&configure)?;
+        legacy::migrate(&configure, &mut disk, &mut index)?;
         Ok(Self {
-            index: Index::new(&configure)?,
+            index,
             disk,
         })
     }
@@ -91,7 +191,7 @@ impl Kernel {
     /// ```
     pub fn read(&mut self, key: &[u8], stream: impl Write) -> Result<()> {
         match self.index.get(key)? {
-            Some(x) => self.disk.read(stream, x),
+            Some((track, index)) => self.disk.read(stream, track, index),
             _ => Err(anyhow!("not found")),
         }
     }
@@ -114,7 +214,7 @@ impl Kernel {
     #[rustfmt::skip]
     pub fn write(&mut self, key: &[u8], stream: impl Read) -> Result<()> {
         if self.index.has(key)? { return Err(anyhow!("not empty")); }
-        self.index.set(key, &self.disk.write(stream)?)
+        self.index.set(key, self.disk.write(stream)?)
     }
 
     /// 删除数据
@@ -134,20 +234,332 @@ impl Kernel {
     pub fn delete(&mut self, key: &[u8]) -> Result<()> {
         match self.index.get(key)? {
             None => Err(anyhow!("not found")),
-            Some(x) => {
-                self.disk.remove(&x)?;
+            Some((track, index)) => {
+                self.disk.remove(track, index)?;
                 self.index.remove(key)
             }
         }
     }
+
+    /// 空闲维护
+    ///
+    /// 需要在空闲时定期调用，
+    /// 具体行为参考`KernelOptions::idle_defrag`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Kernel;
+    ///
+    /// let mut kernel = Kernel::new(
+    ///     "./.static".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ).unwrap();
+    ///
+    /// kernel.maintain().unwrap();
+    /// ```
+    pub fn maintain(&mut self) -> Result<bool> {
+        self.disk.maintain()
+    }
+
+    /// 保存状态
+    ///
+    /// 保存所有轨道未保存的轨道头
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Kernel;
+    ///
+    /// let mut kernel = Kernel::new(
+    ///     "./.static".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ).unwrap();
+    ///
+    /// kernel.flush().unwrap();
+    /// ```
+    pub fn flush(&mut self) -> Result<()> {
+        self.disk.flush()
+    }
+
+    /// 获取全部键
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Kernel;
+    ///
+    /// let kernel = Kernel::new(
+    ///     "./.static".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ).unwrap();
+    ///
+    /// let keys = kernel.keys();
+    /// ```
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.index.keys()
+    }
+
+    /// 转移数据
+    ///
+    /// 按照分片从当前实例读取并写入目标实例，
+    /// 不会将整个数据读入内存，
+    /// 写入完成之后再从当前实例中删除
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::Kernel;
+    ///
+    /// let mut kernel = Kernel::new(
+    ///     "./.static".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ).unwrap();
+    ///
+    /// let mut target = Kernel::new(
+    ///     "./.static2".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ).unwrap();
+    ///
+    /// kernel.transfer(b"test", &mut target).unwrap();
+    /// ```
+    pub fn transfer(&mut self, key: &[u8], target: &mut Kernel) -> Result<()> {
+        let (track, index) = match self.index.get(key)? {
+            Some(head) => head,
+            None => return Err(anyhow!("not found")),
+        };
+
+        let stream = self.disk.open_reader(track, index)?;
+        target.write(key, stream)?;
+        self.delete(key)
+    }
 }
 
 impl KernelOptions {
+    /// 读取缓冲区的长度限制
+    ///
+    /// 取`max_read_buffer`和按照`read_buffer_fraction`
+    /// 计算的系统可用内存比例中较小的值，
+    /// 无法获取可用内存时只使用`max_read_buffer`
+    pub fn read_buffer_limit(&self) -> Option<u64> {
+        let memory = self.read_buffer_fraction.and_then(|fraction| {
+            available_memory().map(|x| (x as f64 * fraction) as u64)
+        });
+
+        match (self.max_read_buffer, memory) {
+            (Some(max), Some(memory)) => Some(max.min(memory)),
+            (max, memory) => max.or(memory),
+        }
+    }
+
+    /// 限制分片大小
+    ///
+    /// 分片大小超过读取缓冲区的长度限制时，
+    /// 将分片大小降低到限制以内（按照4KB对齐），
+    /// 修改时返回原来的分片大小，由调用方决定如何记录.
+    /// 创建存储时不会自动调用，
+    /// 没有调用时过大的分片大小在初始化时返回`KernelError::InvalidOptions`，
+    /// 必须在创建轨道之前调用，已有的轨道无法改变分片大小
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::KernelOptions;
+    ///
+    /// let mut options = KernelOptions::from(
+    ///     "./.static".to_string(), 
+    ///     1024 * 1024 * 1024 * 1
+    /// );
+    ///
+    /// options.chunk_size = 1024 * 1024 * 1024;
+    /// options.max_read_buffer = Some(1024 * 1024);
+    /// if let Some(previous) = options.cap_chunk_size() {
+    ///     println!("chunk_size {} capped to {}", previous, options.chunk_size);
+    /// }
+    /// ```
+    pub fn cap_chunk_size(&mut self) -> Option<u64> {
+        let limit = match self.read_buffer_limit() {
+            Some(limit) if self.chunk_size > limit => limit,
+            _ => return None,
+        };
+
+        let previous = self.chunk_size;
+        self.chunk_size = std::cmp::max(limit / 4096 * 4096, 4096);
+        Some(previous)
+    }
+
+    /// 单个轨道可以保存的数据长度
+    pub fn track_capacity(&self) -> u64 {
+        let size = match self.max_track_file_bytes {
+            Some(max) => self.track_size.min(max),
+            None => self.track_size,
+        };
+
+        let chunks = size.saturating_sub(track::TRACK_HEADER_SIZE) / self.chunk_size;
+        chunks * (self.chunk_size - chunk::HEADER_SIZE as u64)
+    }
+
+    /// 检查配置
+    ///
+    /// 禁止跨轨道时，
+    /// 数据最大长度不能超过单个轨道可以保存的数据长度
+    pub fn validate(&self) -> Result<()> {
+        if self.chunk_size <= chunk::HEADER_SIZE as u64 {
+            return Err(KernelError::InvalidOptions("chunk_size must be larger than the chunk header").into());
+        }
+
+        if let Some(limit) = self.read_buffer_limit() {
+            if self.chunk_size > limit {
+                return Err(KernelError::InvalidOptions("chunk_size exceeds the read buffer limit").into());
+            }
+        }
+
+        if let Some(max) = self.max_object_size {
+            if !self.spill && max > self.track_capacity() {
+                return Err(KernelError::InvalidOptions("max_object_size exceeds track capacity while spill is disabled").into());
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn from(path: String, track_size: u64) -> Self {
         Self {
+            idle_defrag: None,
+            access_stats: false,
+            verify_writes: false,
+            free_bitmap: false,
+            bitmap_rebuild_budget: None,
+            verify_rate: None,
+            free_list_persistence: FreeListPersistence::Eager,
+            compact_trigger: None,
+            max_track_file_bytes: None,
+            max_object_size: None,
+            spill: true,
+            max_read_buffer: None,
+            read_buffer_fraction: None,
+            strict_flags: false,
+            lock: false,
+            force_lock: false,
             chunk_size: 4096,
             track_size,
             path,
         }
     }
 }
+
+/// 系统可用内存
+///
+/// 读取`/proc/meminfo`中的`MemAvailable`，
+/// 无法读取时返回空
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|x| x.starts_with("MemAvailable:"))?;
+    let size = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(size * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{available_memory, IdleDefrag, Kernel, KernelError, KernelOptions};
+    use std::time::Duration;
+
+    fn options(path: &std::path::Path, idle: Duration) -> KernelOptions {
+        let mut options = KernelOptions::from(path.to_str().unwrap().to_string(), 1024 * 1024);
+        options.idle_defrag = Some(IdleDefrag { idle, io_budget: 1024 });
+        options
+    }
+
+    #[test]
+    fn maintain_releases_free_tail_when_idle() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kernel = Kernel::with_options(options(dir.path(), Duration::from_millis(0))).unwrap();
+        let data = vec![7u8; 4067 * 3 + 5];
+        for key in 0..6u8 {
+            kernel.write(&[key], &data[..]).unwrap();
+        }
+
+        kernel.delete(&[1]).unwrap();
+        kernel.delete(&[5]).unwrap();
+        kernel.delete(&[3]).unwrap();
+        let track = dir.path().join("1.track");
+        let before = std::fs::metadata(&track).unwrap().len();
+        assert!(kernel.maintain().unwrap());
+
+        // 空闲链表排序之后尾部的空闲分片被截断
+        let after = std::fs::metadata(&track).unwrap().len();
+        assert_eq!(before - after, 4 * 4096);
+
+        let mut out = Vec::new();
+        kernel.read(&[4], &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn transfer_streams_into_target() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let mut kernel = Kernel::with_options(options(source.path(), Duration::from_secs(3600))).unwrap();
+        let mut other = Kernel::with_options(options(target.path(), Duration::from_secs(3600))).unwrap();
+        let data = (0..4067 * 300 + 11).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+        kernel.write(b"a", &data[..]).unwrap();
+
+        kernel.transfer(b"a", &mut other).unwrap();
+        let mut out = Vec::new();
+        other.read(b"a", &mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(kernel.read(b"a", Vec::new()).is_err());
+        assert!(kernel.transfer(b"a", &mut other).is_err());
+    }
+
+    #[test]
+    fn maintain_waits_for_idle() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut kernel = Kernel::with_options(options(dir.path(), Duration::from_secs(3600))).unwrap();
+        kernel.write(b"a", &[1u8; 100][..]).unwrap();
+        kernel.delete(b"a").unwrap();
+        assert!(!kernel.maintain().unwrap());
+    }
+
+    #[test]
+    fn chunk_size_is_capped_to_read_buffer_limit() {
+        let mut options = KernelOptions::from("./.static".to_string(), 1024 * 1024 * 64);
+        options.chunk_size = 1024 * 1024 * 1024 * 8;
+        assert_eq!(options.read_buffer_limit(), None);
+        assert_eq!(options.cap_chunk_size(), None);
+
+        options.max_read_buffer = Some(1024 * 1024 + 100);
+        let error = options.validate().unwrap_err();
+        assert!(matches!(error.downcast_ref::<KernelError>(), Some(KernelError::InvalidOptions(_))));
+        assert_eq!(options.cap_chunk_size(), Some(1024 * 1024 * 1024 * 8));
+        assert_eq!(options.chunk_size, 1024 * 1024);
+        assert_eq!(options.cap_chunk_size(), None);
+        options.validate().unwrap();
+    }
+
+    #[test]
+    fn chunk_size_is_capped_to_available_memory() {
+        let memory = match available_memory() {
+            Some(memory) => memory,
+            None => return,
+        };
+
+        let mut options = KernelOptions::from("./.static".to_string(), 1024 * 1024 * 64);
+        options.chunk_size = u64::MAX / 2;
+        options.read_buffer_fraction = Some(0.5);
+
+        // 可用内存随时在变化，只检查大致的比例
+        let limit = options.read_buffer_limit().unwrap();
+        assert!(limit.abs_diff(memory / 2) < memory / 10, "{} of {}", limit, memory);
+
+        // 显式配置的上限更小时使用显式上限
+        options.max_read_buffer = Some(1024 * 1024);
+        assert_eq!(options.read_buffer_limit(), Some(1024 * 1024));
+        options.max_read_buffer = None;
+
+        assert!(options.validate().is_err());
+        assert_eq!(options.cap_chunk_size(), Some(u64::MAX / 2));
+        assert!(options.chunk_size <= limit + memory / 10);
+        assert_eq!(options.chunk_size % 4096, 0);
+    }
+}
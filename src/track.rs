@@ -1,5 +1,7 @@
 use anyhow::Result;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use std::rc::Rc;
 use bytes::{
     Buf, 
@@ -9,18 +11,80 @@ use bytes::{
 };
 
 use super::{
-    fs::Fs,
-    chunk::Codec,
+    bitmap::Bitmap,
+    fs::{lock_dir, Fs},
+    chunk::{Chunk, Codec, Header, FLAG_FREE, FLAG_HEAD, FLAG_KNOWN, HEADER_SIZE},
+    error::KernelError,
+    FreeListPersistence,
     KernelOptions
 };
 
+/// 轨道文件头长度
+pub const TRACK_HEADER_SIZE: u64 = 40;
+
+/// 轨道文件标识
+pub const TRACK_MAGIC: &[u8; 4] = b"PHYT";
+
+/// 轨道文件格式版本
+const TRACK_VERSION: u16 = 1;
+
+/// 轨道状态：轨道已经正常关闭
+const TRACK_CLOSED: u16 = 1;
+
+/// 未完成的位图重建
+///
+/// `cursor` 下个需要扫描的分片  
+/// `end` 开始重建时的轨道长度，之后分配的分片不需要扫描  
+/// `bits` 已经扫描到的失效分片
+struct Rebuild {
+    cursor: u64,
+    end: u64,
+    bits: Vec<u64>,
+}
+
 /// 存储轨道
 ///
 /// 数据存储在轨道文件内，
 /// 数据被拆分成固定大小的分片以链表形式写入，
 /// 删除数据只会标记分片为失效，下次写入将覆盖分片
+///
+/// #### bitmap
+/// 开启`free_bitmap`时使用位图记录失效分片，
+/// 位图保存在`<id>.free`文件中，
+/// 此时轨道头的失效链表首尾位置始终为0，
+/// 失效分片同时设置`FLAG_FREE`标记，
+/// 轨道没有正常关闭或者位图文件丢失时按照分片标记重建位图，
+/// 超出`bitmap_rebuild_budget`时剩余的扫描推迟到第一次需要位图时完成，
+/// 期间读取不受影响，重建完成之前关闭的轨道仍然视为没有正常关闭.
+/// 位图没有放在轨道文件头部的预留区域，
+/// 因为预留区域的长度取决于轨道长度，
+/// 开启或者关闭位图会移动所有分片的位置，
+/// 单独的文件可以在已有的轨道上随时切换
+///
+/// #### dirty
+/// 轨道头是否有未保存的修改，
+/// 延迟保存失效链表时由`flush`或者释放轨道时保存
+///
+/// #### torn
+/// 异常退出时轨道文件末尾可能留下不完整的分片，
+/// 初始化时截掉不完整的部分，
+/// 读取该位置时返回`KernelError::ShortRead`，
+/// 该位置重新从尾部分配之后清除
+///
+/// #### generation
+/// 从轨道尾部分配的新分片使用的代数，
+/// 尾部分片被截断时更新为截断分片的代数加一，
+/// 保存在轨道头中，
+/// 避免截断之前的引用在重新分配之后通过代数检查
 pub struct Track {
     options: Rc<KernelOptions>,
+    generation: u16,
+    torn: Option<u64>,
+    closed: bool,
+    dirty: bool,
+    bitmap: Option<Bitmap>,
+    rebuild: Option<Rebuild>,
+    reused: HashMap<u64, u16>,
     buffer: Vec<u8>,
     free_start: u64,
     real_size: u64,
@@ -28,10 +92,15 @@ pub struct Track {
     chunk: Codec,
     size: u64,
     file: Fs,
+    id: u16,
 }
 
 impl Track {
-    /// 创建轨道
+    /// 打开轨道
+    ///
+    /// 轨道文件必须已经存在，
+    /// 不存在时返回`KernelError::MissingTrack`，
+    /// 新的轨道使用`create`创建
     ///
     /// ```no_run
     /// use super::{Track, KernelOptions};
@@ -45,18 +114,76 @@ impl Track {
     /// let track = Track::new(0, options).unwrap();
     /// ```
     pub fn new(id: u16, options: Rc<KernelOptions>) -> Result<Track> {
-        let path: &Path = options.path.as_ref();
-        let track_path = path.join(format!("{}.track", id));
-        Ok(Self {
+        let path = Self::track_path(id, &options);
+        if !path.exists() {
+            return Err(KernelError::MissingTrack(id).into());
+        }
+
+        let file = Fs::new(path)?;
+        Ok(Self::from_file(id, options, file))
+    }
+
+    /// 创建新轨道
+    ///
+    /// 持有目录锁创建轨道文件并立即写入默认头索引，
+    /// 多个写入同时创建同一个轨道时只有一个能创建成功，
+    /// 其他的等待目录锁释放之后打开已有的轨道，
+    /// 持有锁时已有文件仍然不足文件头长度，
+    /// 说明文件已经损坏，返回`KernelError::InvalidTrack`，
+    /// 不会截断或者覆盖已经写入的文件.
+    /// 打开已有的轨道使用`new`，不会等待
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let track = Track::create(0, options).unwrap();
+    /// ```
+    pub fn create(id: u16, options: Rc<KernelOptions>) -> Result<Track> {
+        let path = Self::track_path(id, &options);
+
+        // 创建文件和写入文件头都在锁内完成，
+        // 拿到锁之后文件头一定已经写入
+        let _guard = lock_dir(&options.path)?;
+        if let Some(file) = Fs::create_new(&path)? {
+            let mut track = Self::from_file(id, options, file);
+            track.default_header()?;
+            track.file.flush()?;
+            return Ok(track);
+        }
+
+        if std::fs::metadata(&path)?.len() < TRACK_HEADER_SIZE {
+            return Err(KernelError::InvalidTrack(id).into());
+        }
+
+        Self::new(id, options)
+    }
+
+    /// 从文件句柄创建轨道类
+    fn from_file(id: u16, options: Rc<KernelOptions>, file: Fs) -> Track {
+        Self {
             buffer: vec![0u8; options.chunk_size as usize],
             chunk: Codec::new(options.clone()),
-            file: Fs::new(track_path)?,
+            file,
+            reused: HashMap::new(),
+            bitmap: None,
+            rebuild: None,
+            generation: 0,
+            torn: None,
+            closed: false,
+            dirty: false,
             free_start: 0,
             real_size: 0,
             free_end: 0,
             size: 0,
             options,
-        })
+            id,
+        }
     }
 
     /// 初始化
@@ -80,12 +207,111 @@ impl Track {
     /// ```
     pub fn init(&mut self) -> Result<()> {
         self.real_size = self.file.stat()?.len();
-        self.read_header()
+        self.read_header()?;
+
+        // 轨道长度按照分片对齐，
+        // 保证从尾部分配的位置始终对齐
+        let torn = (self.real_size - TRACK_HEADER_SIZE) % self.options.chunk_size;
+        if torn > 0 {
+            self.real_size -= torn;
+            self.torn = Some(self.real_size);
+        }
+
+        // 切换失效分片记录方式时，
+        // 将已有的失效分片转换到新的记录方式
+        let path = self.bitmap_path();
+        if self.options.free_bitmap {
+            let mut list = self.walk_free(u64::MAX)?.unwrap();
+            // 位图文件丢失时即使正常关闭也需要重建
+            let missing = !path.exists();
+            self.bitmap = Some(Bitmap::new(path)?);
+            if !self.closed || missing {
+                let deadline = self.options.bitmap_rebuild_budget.map(|x| Instant::now() + x);
+                self.rebuild = Some(Rebuild { cursor: TRACK_HEADER_SIZE, end: self.real_size, bits: Vec::new() });
+                self.rebuild_bitmap(deadline)?;
+            }
+
+            if !list.is_empty() {
+                for offset in &list {
+                    self.mark_free(*offset)?;
+                }
+
+                list.extend(self.walk_free(u64::MAX)?.unwrap());
+                self.release(list)?;
+            }
+        } else if path.exists() {
+            let mut bitmap = Bitmap::new(path)?;
+            let bits = bitmap.bits();
+            if !bits.is_empty() {
+                let mut list = self.walk_free(u64::MAX)?.unwrap();
+                list.extend(bits.into_iter().map(|x| self.bit_offset(x)));
+                self.release(list)?;
+                bitmap.reset(&[])?;
+            }
+        }
+
+        // 打开期间轨道状态为未关闭，
+        // 异常退出之后下次打开时可以发现
+        self.closed = false;
+        self.flush()
+    }
+
+    /// 重建位图
+    ///
+    /// 按照分片的`FLAG_FREE`标记校验位图，
+    /// 和位图不一致时使用分片标记重写位图，
+    /// 到达`deadline`时保留扫描进度并返回，
+    /// 没有未完成的重建时直接返回
+    fn rebuild_bitmap(&mut self, deadline: Option<Instant>) -> Result<()> {
+        let chunk_size = self.options.chunk_size;
+        let rebuild = match self.rebuild.as_mut() {
+            Some(rebuild) => rebuild,
+            None => return Ok(()),
+        };
+
+        let mut flags = [0u8; 1];
+        while rebuild.cursor < rebuild.end.min(self.real_size) {
+            if deadline.is_some_and(|x| Instant::now() >= x) {
+                return Ok(());
+            }
+
+            self.file.intact_read(&mut flags, rebuild.cursor + 16)?;
+            if flags[0] & FLAG_FREE != 0 {
+                rebuild.bits.push((rebuild.cursor - TRACK_HEADER_SIZE) / chunk_size);
+            }
+
+            rebuild.cursor += chunk_size;
+        }
+
+        let bitmap = self.bitmap.as_mut().unwrap();
+        if bitmap.bits() != rebuild.bits {
+            bitmap.reset(&rebuild.bits)?;
+        }
+
+        self.rebuild = None;
+        Ok(())
+    }
+
+    /// 位图是否还在重建
+    ///
+    /// 初始化超出重建预算时为`true`，
+    /// 分配失效分片、删除数据和读取失效分片列表之前会先完成重建
+    pub fn is_rebuilding(&self) -> bool {
+        self.rebuild.is_some()
+    }
+
+    /// 标记分片失效
+    ///
+    /// 位图模式下失效分片只保留`FLAG_FREE`标记
+    fn mark_free(&mut self, offset: u64) -> Result<()> {
+        self.file.write(&[FLAG_FREE], offset + 16)
     }
 
     /// 读取分片数据
     ///
-    /// 读取单个分片数据
+    /// 读取单个分片数据，
+    /// 位置超出轨道范围时返回`KernelError::DanglingPointer`，
+    /// 文件在分片中间结束时返回`KernelError::ShortRead`
     ///
     /// # Examples
     ///
@@ -103,16 +329,121 @@ impl Track {
     /// 
     /// let chunk = track.read(10).unwrap();
     /// ```
-    pub fn read(&mut self, offset: u64) -> Result<(Option<u64>, &[u8])> {
-        self.file.intact_read(&mut self.buffer, offset)?;
-        Ok(self.chunk.decoder(&self.buffer[..]))
+    pub fn read(&mut self, offset: u64) -> Result<Chunk<'_>> {
+        self.validate_offset(offset)?;
+        if offset + self.options.chunk_size > self.real_size {
+            return Err(self.out_of_range(offset));
+        }
+
+        // 分片数据长度超出分片范围时不能解码
+        let size = self.buffer.len();
+        self.read_full(size, offset)?;
+        if self.chunk.header(&self.buffer[..HEADER_SIZE]).size + HEADER_SIZE > size {
+            return Err(KernelError::InvalidSize(self.id, offset).into());
+        }
+
+        let chunk = self.chunk.decoder(&self.buffer[..]);
+        self.check_flags(chunk.flags, offset)?;
+        Ok(chunk)
+    }
+
+    /// 检查分片位置
+    ///
+    /// 分片位置必须位于轨道头之后，
+    /// 并且和分片大小对齐，
+    /// 位于轨道头区域时返回`KernelError::CorruptChain`，
+    /// 没有对齐时返回`KernelError::UnalignedOffset`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let track = Track::new(0, options).unwrap();
+    /// assert!(track.validate_offset(40).is_ok());
+    /// assert!(track.validate_offset(41).is_err());
+    /// ```
+    pub fn validate_offset(&self, offset: u64) -> Result<()> {
+        if offset > 0 && offset < TRACK_HEADER_SIZE {
+            return Err(KernelError::CorruptChain(offset).into());
+        }
+
+        let chunk_size = self.options.chunk_size;
+        match offset >= TRACK_HEADER_SIZE && (offset - TRACK_HEADER_SIZE).is_multiple_of(chunk_size) {
+            false => Err(KernelError::UnalignedOffset(offset).into()),
+            true => Ok(()),
+        }
+    }
+
+    /// 读取分片头
+    ///
+    /// 只读取分片头，
+    /// 不读取分片数据
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    /// 
+    /// let header = track.header(40).unwrap();
+    /// ```
+    pub fn header(&mut self, offset: u64) -> Result<Header> {
+        self.validate_offset(offset)?;
+        if offset + self.options.chunk_size > self.real_size {
+            return Err(self.out_of_range(offset));
+        }
+
+        self.read_full(HEADER_SIZE, offset)?;
+        let header = self.chunk.header(&self.buffer[..HEADER_SIZE]);
+        self.check_flags(header.flags, offset)?;
+        Ok(header)
+    }
+
+    /// 超出轨道范围的分片位置
+    ///
+    /// 文件末尾不完整的分片返回`KernelError::ShortRead`，
+    /// 其他位置返回`KernelError::DanglingPointer`
+    fn out_of_range(&self, offset: u64) -> anyhow::Error {
+        match self.torn == Some(offset) {
+            true => KernelError::ShortRead(self.id, offset).into(),
+            false => KernelError::DanglingPointer(self.id, offset).into(),
+        }
+    }
+
+    /// 检查分片标记
+    ///
+    /// 严格模式下标记包含不认识的位时返回错误，
+    /// 避免按照旧的格式错误解读新版本写入的分片
+    fn check_flags(&self, flags: u8, offset: u64) -> Result<()> {
+        match self.options.strict_flags && flags & !FLAG_KNOWN != 0 {
+            true => Err(KernelError::UnknownFlags(offset).into()),
+            false => Ok(()),
+        }
     }
 
     /// 分配分片写入位置
     ///
     /// 因为链表的特殊性，
     /// 所以这个地方并不直接写入数据，
-    /// 而是预先分配位置
+    /// 而是预先分配位置，
+    /// 从失效链表复用的分片将在写入时递增代数，
+    /// 轨道文件长度不会超过`track_size`和`max_track_file_bytes`，
+    /// 没有可用位置时返回`None`由写入流切换到新的轨道
     ///
     /// # Examples
     ///
@@ -132,18 +463,39 @@ impl Track {
     /// ```
     pub fn alloc(&mut self) -> Result<Option<u64>> {
         let chunk_size = self.options.chunk_size;
-        let track_size = self.options.track_size;
+        let track_size = match self.options.max_track_file_bytes {
+            Some(max) => self.options.track_size.min(max),
+            None => self.options.track_size,
+        };
+
         let free_start = self.free_start;
         let real_size = self.real_size;
 
         // 避免写入放大(WAF)
         // 先写入轨道文件尾部
         if real_size + chunk_size <= track_size {
+            self.torn = self.torn.filter(|x| *x != real_size);
             self.real_size += chunk_size;
             self.size += chunk_size;
             return Ok(Some(real_size));
         }
 
+        // 位图模式直接扫描第一个失效分片
+        self.rebuild_bitmap(None)?;
+        if let Some(bitmap) = self.bitmap.as_mut() {
+            let bit = match bitmap.first() {
+                Some(bit) => bit,
+                None => return Ok(None),
+            };
+
+            bitmap.remove(bit)?;
+            let offset = self.bit_offset(bit);
+            let mut buffer = [0u8; 2];
+            self.file.intact_read(&mut buffer, offset + 14)?;
+            self.reused.insert(offset, u16::from_be_bytes(buffer).wrapping_add(1));
+            return Ok(Some(offset));
+        }
+
         // 没有失效块
         // 并且轨道不够写入
         if free_start == 0 {
@@ -151,21 +503,24 @@ impl Track {
         }
 
         // 读取失效分片
-        // 并解码失效分片
-        let mut buffer = [0u8; 8];
+        // 复用分片的代数为上次代数加一
+        let mut buffer = [0u8; 16];
         self.file.read(&mut buffer, free_start)?;
-        let next = u64::from_be_bytes(buffer);
+        let mut packet = &buffer[..];
+        let next = packet.get_u64();
+        packet.advance(6);
+        self.reused.insert(free_start, packet.get_u16().wrapping_add(1));
 
-        // 检查失效分片是否已经分配完成
-        // 如果分配完整则重置失效分片状态
-        Ok(if self.free_end > 0 && next == self.free_end {
+        // 如果失效链表只剩最后一个分片
+        // 则重置失效分片状态
+        if free_start == self.free_end {
             self.free_start = 0;
             self.free_end = 0;
-            None
         } else {
             self.free_start = next;
-            Some(free_start)
-        })
+        }
+
+        Ok(Some(free_start))
     }
 
     /// 删除数据
@@ -175,7 +530,8 @@ impl Track {
     /// 所以这里只用给定头部分片，
     /// 内部将一直根据链表索引删除下去，
     /// 当遇到跳出当前轨道去往其他轨道的时候，
-    /// 将返回其他轨道的ID
+    /// 将返回其他轨道的ID和分片位置，
+    /// 同时返回当前轨道内最后一个分片的位置
     ///
     /// # Examples
     ///
@@ -191,140 +547,1187 @@ impl Track {
     /// let mut track = Track::new(0, options).unwrap();
     /// track.init().unwrap();
     ///
-    /// let track_id = track.remove(10).unwrap();
+    /// let (last, next) = track.remove(40).unwrap();
     /// ```
     #[rustfmt::skip]
-    pub fn remove(&mut self, alloc_map: &Vec<u64>) -> Result<()> {
-        assert!(alloc_map.len() > 0);
-        
-        // 获取头部索引
-        // 获取尾部索引
-        let first = alloc_map.first().unwrap();
-        let last = alloc_map.last().unwrap();
-        
-        // 失效索引尾部更新
-        // 更新为当前尾部位置
-        self.free_end = *last;
+    pub fn remove(&mut self, index: u64) -> Result<(u64, Option<(u16, u64)>)> {
+        self.validate_offset(index)?;
+        let id = self.id;
+
+        // 沿着链表找到当前轨道内的尾部分片，
+        // 以及跳出当前轨道之后的下个分片
+        let mut last = index;
+        let mut chain = vec![index];
+        let mut flags = None;
+        let next = loop {
+            let chunk = self.read(last)?;
+            flags.get_or_insert(chunk.flags);
+            match chunk.next {
+                Some((track, offset)) if track == id => {
+                    chain.push(offset);
+                    last = offset;
+                },
+                next => break next,
+            }
+        };
+
+        // 清除头部分片标记，
+        // 指向已删除数据的外部引用读取时可以立即发现
+        if let Some(flags) = flags.filter(|x| x & FLAG_HEAD != 0) {
+            self.file.write(&[flags & !FLAG_HEAD], index + 16)?;
+        }
+
+        // 位图模式标记链表上的所有分片
+        if self.bitmap.is_some() {
+            self.rebuild_bitmap(None)?;
+            for offset in chain {
+                self.mark_free(offset)?;
+                let bit = self.offset_bit(offset);
+                self.bitmap.as_mut().unwrap().insert(bit)?;
+            }
+
+            return Ok((last, next));
+        }
         
         // 如果当前没有已失效的块
         // 则直接更新头部索引
         // 如果存在则首尾链接
         if self.free_start > 0 {
-            let next_buf = first.to_be_bytes();
+            let next_buf = index.to_be_bytes();
             self.file.write(&next_buf, self.free_end)?;
         } else {
-            self.free_start = *first;
+            self.free_start = index;
         }
+
+        // 失效索引尾部更新
+        // 更新为当前尾部位置
+        self.free_end = last;
         
-        // 保存状态
-        self.flush()
+        // 按照持久化策略保存状态，
+        // 延迟保存时只标记轨道头需要保存
+        match self.options.free_list_persistence {
+            FreeListPersistence::Eager => self.flush()?,
+            FreeListPersistence::Lazy => self.dirty = true,
+        }
+
+        Ok((last, next))
     }
 
-    /// 写入分片
+    /// 整理失效链表
     ///
-    /// 写入单个分片数据到磁盘文件
+    /// 将失效链表按照偏移排序并重新链接，
+    /// 位于轨道文件尾部的连续失效分片将被截断，
+    /// 整理过程不会移动任何有效数据，
+    /// `budget`为本次整理允许的分片读写次数，
+    /// 预算不足时不做任何修改并返回`false`，
+    /// 链表已经有序并且尾部没有失效分片时直接返回
     ///
     /// # Examples
     ///
     /// ```no_run
-    /// use super::{Track, Chunk, KernelOptions};
+    /// use super::{Track, KernelOptions};
     /// use std::rc::Rc;
     ///
-    /// let chunk = Chunk {
-    ///     next: Some(17),
-    ///     data: Bytes::from_static(b"hello"),
-    /// };
-    ///
     /// let options = Rc::new(KernelOptions::from(
-    ///     Path::new("./.static"), 
+    ///     Path::new("./.static"),
     ///     1024 * 1024 * 1024 * 1
     /// ));
     ///
     /// let mut track = Track::new(0, options).unwrap();
     /// track.init().unwrap();
     ///
-    /// track.write(&chunk, 20).unwrap();
+    /// let mut budget = 1024;
+    /// track.tidy(&mut budget).unwrap();
     /// ```
-    pub fn write(&mut self, next: Option<u64>, chunk: &[u8], index: u64) -> Result<()> {
-        self.file.write(&self.chunk.encoder(next, chunk), index)
+    pub fn tidy(&mut self, budget: &mut u64) -> Result<bool> {
+
+        // 位图本身有序，
+        // 只需要截断尾部并重写位图
+        if self.bitmap.is_some() {
+            let list = self.free_list()?;
+            if !self.has_free_tail(&list) {
+                return Ok(true);
+            }
+
+            if *budget == 0 {
+                return Ok(false);
+            }
+
+            *budget -= 1;
+            self.release(list)?;
+            return Ok(true);
+        }
+
+        // 遍历链表时除了尾部分片都需要读取一次，
+        // 重新链接需要同样次数的写入，
+        // 另外还需要保存一次轨道头
+        let list = match self.walk_free(budget.saturating_sub(1) / 2)? {
+            Some(list) => list,
+            None => return Ok(false),
+        };
+
+        let reads = list.len().saturating_sub(1) as u64;
+        let sorted = list.windows(2).all(|x| x[0] < x[1]);
+        if sorted && !self.has_free_tail(&list) {
+            *budget -= reads;
+            return Ok(true);
+        }
+
+        *budget -= reads * 2 + 1;
+        self.release(list)?;
+        Ok(true)
     }
 
-    /// 写入结束
+    /// 失效分片中是否存在位于轨道尾部的分片
+    fn has_free_tail(&self, list: &[u64]) -> bool {
+        let chunk_size = self.options.chunk_size;
+        list.iter().any(|x| x + chunk_size == self.real_size)
+    }
+
+    /// 获取失效分片列表
     ///
-    /// 当数据流写入完成的时候，
-    /// 将状态同步到磁盘文件，
-    /// 这是一个必要的操作，
-    /// 但是不强制什么时候调用，
-    /// 不过一定要在关闭实例之前调用一次
+    /// 按照失效链表顺序返回所有失效分片的偏移
     ///
     /// # Examples
     ///
     /// ```no_run
-    /// use super::{Track, Chunk, KernelOptions};
+    /// use super::{Track, KernelOptions};
     /// use std::rc::Rc;
     ///
-    /// let chunk = Chunk {
-    ///     next: Some(17),
-    ///     data: Bytes::from_static(b"hello"),
-    /// };
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let free = track.free_list().unwrap();
+    /// ```
+    pub fn free_list(&mut self) -> Result<Vec<u64>> {
+        Ok(self.walk_free(u64::MAX)?.unwrap())
+    }
+
+    /// 获取所有头部分片
+    ///
+    /// 遍历轨道内所有有效分片，
+    /// 返回带有头部标记的分片偏移
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
     ///
     /// let options = Rc::new(KernelOptions::from(
-    ///     Path::new("./.static"), 
+    ///     Path::new("./.static"),
     ///     1024 * 1024 * 1024 * 1
     /// ));
     ///
     /// let mut track = Track::new(0, options).unwrap();
     /// track.init().unwrap();
     ///
-    /// track.write(Chunk, 20).unwrap();
-    /// track.flush().unwrap();
+    /// let heads = track.heads().unwrap();
     /// ```
-    pub fn flush(&mut self) -> Result<()> {
-        let mut packet = BytesMut::new();
-        packet.put_u64(self.free_start);
-        packet.put_u64(self.free_end);
-        packet.put_u64(self.size);
-        self.file.write(&packet, 0)?;
-        self.file.flush()
+    pub fn heads(&mut self) -> Result<Vec<u64>> {
+        let free = self.free_list()?.into_iter().collect::<HashSet<u64>>();
+        let mut heads = Vec::new();
+        for offset in self.offsets().collect::<Vec<u64>>() {
+            if !free.contains(&offset) && self.header(offset)?.is_head() {
+                heads.push(offset);
+            }
+        }
+
+        Ok(heads)
     }
 
-    /// 创建默认文件头
+    /// 获取分片填充比例
     ///
-    /// 将默认的失效块头索引和尾部索引写入到磁盘文件,
-    /// 并初始化文件长度状态
-    fn default_header(&mut self) -> Result<()> {
-        let mut buf = BytesMut::new();
-        buf.put_u64(0);
-        buf.put_u64(0);
-        buf.put_u64(24);
-        self.file.write(&buf, 0)?;
-        self.real_size = 24;
-        self.size = 24;
-        Ok(())
+    /// 只读取有效分片的分片头，
+    /// 返回分片数据区域中未使用的填充部分所占的比例，
+    /// 比例较高说明分片大小超过数据需要
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let waste = track.padding_waste().unwrap();
+    /// ```
+    pub fn padding_waste(&mut self) -> Result<f64> {
+        let free = self.free_list()?.into_iter().collect::<HashSet<u64>>();
+        let diff_size = self.options.chunk_size - HEADER_SIZE as u64;
+        let mut capacity = 0;
+        let mut used = 0;
+        for offset in self.offsets().collect::<Vec<u64>>() {
+            if !free.contains(&offset) {
+                used += self.header(offset)?.size as u64;
+                capacity += diff_size;
+            }
+        }
+
+        Ok(match capacity {
+            0 => 0.0,
+            _ => (capacity - used) as f64 / capacity as f64,
+        })
     }
 
-    /// 读取文件头
+    /// 获取连续失效分片的分布
     ///
-    /// 从磁盘文件中读取失效块头索引和尾部索引，
-    /// 这是必要的操作，轨道实例化的时候必须要
-    /// 从文件中恢复上次的状态
-    fn read_header(&mut self) -> Result<()> {
-        // 如果文件为空
-        // 则直接写入默认头索引
-        if self.real_size == 0 {
-            return self.default_header();
+    /// 将失效分片按照偏移排序，
+    /// 相邻的失效分片组成连续区域，
+    /// 返回区域长度(分片数量)和区域数量，按照长度排序，
+    /// 少量长区域说明失效空间集中，
+    /// 大量短区域说明碎片较多
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let histogram = track.free_run_histogram().unwrap();
+    /// ```
+    pub fn free_run_histogram(&mut self) -> Result<Vec<(u64, u64)>> {
+        let chunk_size = self.options.chunk_size;
+        let mut list = self.free_list()?;
+        list.sort_unstable();
+
+        let mut runs: BTreeMap<u64, u64> = BTreeMap::new();
+        let mut start = 0;
+        for i in 1..=list.len() {
+            if i == list.len() || list[i] != list[i - 1] + chunk_size {
+                *runs.entry((i - start) as u64).or_default() += 1;
+                start = i;
+            }
         }
 
-        // 从文件中读取头部
-        let mut buffer = [0u8; 24];
-        self.file.read(&mut buffer, 0)?;
-        let mut packet = Bytes::from(buffer.to_vec());
+        Ok(runs.into_iter().collect())
+    }
 
-        // 将状态同步到实例内部
-        self.free_start = packet.get_u64();
-        self.free_end = packet.get_u64();
-        self.size = packet.get_u64();
-        
-        Ok(())
+    /// 获取所有分片的偏移
+    ///
+    /// 包括有效分片和失效分片
+    pub fn offsets(&self) -> impl Iterator<Item = u64> {
+        (TRACK_HEADER_SIZE..self.real_size).step_by(self.options.chunk_size as usize)
+    }
+
+    /// 迁移分片
+    ///
+    /// 将分片原样复制到目标位置，
+    /// 目标位置必须是已经失效的分片，
+    /// 迁移后分片代数为目标位置上次代数加一，
+    /// 源位置清除头部标记并递增代数，
+    /// 位图模式下同时设置失效标记，
+    /// 指向源位置的旧引用读取时可以立即发现，
+    /// 返回迁移分片的下个分片位置，
+    /// 前驱分片的链接需要外部通过`link`更新
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let next = track.relocate(8232, 40).unwrap();
+    /// ```
+    pub fn relocate(&mut self, src: u64, dst: u64) -> Result<Option<(u16, u64)>> {
+        let mut buffer = [0u8; 2];
+        self.file.intact_read(&mut buffer, dst + 14)?;
+        let generation = u16::from_be_bytes(buffer).wrapping_add(1);
+
+        self.file.intact_read(&mut self.buffer, src)?;
+        let stale = u16::from_be_bytes([self.buffer[14], self.buffer[15]]).wrapping_add(1);
+        let mut packet = stale.to_be_bytes().to_vec();
+        packet.push(match self.bitmap.is_some() {
+            true => self.buffer[16] & !FLAG_HEAD | FLAG_FREE,
+            false => self.buffer[16] & !FLAG_HEAD,
+        });
+
+        self.buffer[14..16].copy_from_slice(&generation.to_be_bytes());
+        self.file.write(&self.buffer, dst)?;
+        self.file.write(&packet, src + 14)?;
+        Ok(self.chunk.decoder(&self.buffer).next)
+    }
+
+    /// 记录数据读取
+    ///
+    /// 递增头部分片的读取次数并更新最后读取时间，
+    /// 只写入统计字段，不会强制同步到磁盘
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// track.touch(40, 1600000000000).unwrap();
+    /// ```
+    pub fn touch(&mut self, offset: u64, now: u64) -> Result<()> {
+        self.validate_offset(offset)?;
+        let mut buffer = [0u8; 4];
+        self.file.intact_read(&mut buffer, offset + 17)?;
+        let hits = u32::from_be_bytes(buffer).saturating_add(1);
+
+        let mut packet = BytesMut::new();
+        packet.put_u32(hits);
+        packet.put_u64(now);
+        self.file.write(&packet, offset + 17)
+    }
+
+    /// 更新分片链接
+    ///
+    /// 只修改分片的下个分片位置，
+    /// 不修改分片内容
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// track.link(40, (1, 4136)).unwrap();
+    /// ```
+    pub fn link(&mut self, offset: u64, (track, next): (u16, u64)) -> Result<()> {
+        self.file.write(&next.to_be_bytes(), offset)?;
+        self.file.write(&track.to_be_bytes(), offset + 12)
+    }
+
+    /// 释放未写入的分片
+    ///
+    /// 将分配之后没有写入的分片直接追加到失效链表或者位图，
+    /// 位于轨道文件尾部的分片将被直接截断，
+    /// 不会重新排序已有的失效链表，整理留给`tidy`，
+    /// 轨道头按照`free_list_persistence`保存
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let index = track.alloc().unwrap().unwrap();
+    /// track.free(vec![index]).unwrap();
+    /// ```
+    pub fn free(&mut self, mut offsets: Vec<u64>) -> Result<()> {
+        self.rebuild_bitmap(None)?;
+        offsets.sort_unstable();
+        if self.truncate_tail(&mut offsets)? {
+            self.file.truncate(self.real_size)?;
+            self.torn = None;
+        }
+
+        // 位图模式标记所有分片，
+        // 否则按照偏移顺序追加到失效链表尾部
+        if self.bitmap.is_some() {
+            for offset in offsets {
+                self.mark_free(offset)?;
+                let bit = self.offset_bit(offset);
+                self.bitmap.as_mut().unwrap().insert(bit)?;
+            }
+        } else {
+            for offset in offsets {
+                match self.free_start > 0 {
+                    true => self.file.write(&offset.to_be_bytes(), self.free_end)?,
+                    false => self.free_start = offset,
+                }
+
+                self.free_end = offset;
+            }
+        }
+
+        match self.options.free_list_persistence {
+            FreeListPersistence::Eager => self.flush(),
+            FreeListPersistence::Lazy => {
+                self.dirty = true;
+                Ok(())
+            },
+        }
+    }
+
+    /// 重建失效链表
+    ///
+    /// 将给定的失效分片按照偏移排序并重新链接，
+    /// 位于轨道文件尾部的连续失效分片将被截断
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"),
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let free = track.free_list().unwrap();
+    /// track.release(free).unwrap();
+    /// ```
+    pub fn release(&mut self, mut list: Vec<u64>) -> Result<()> {
+        self.rebuild_bitmap(None)?;
+        list.sort_unstable();
+        self.truncate_tail(&mut list)?;
+
+        // 位图模式重写位图，
+        // 否则按照偏移顺序重新链接失效分片
+        if let Some(bitmap) = self.bitmap.as_mut() {
+            let chunk_size = self.options.chunk_size;
+            let bits = list.iter().map(|x| (x - TRACK_HEADER_SIZE) / chunk_size).collect::<Vec<u64>>();
+            bitmap.reset(&bits)?;
+            list.clear();
+        }
+
+        for pair in list.windows(2) {
+            self.file.write(&pair[1].to_be_bytes(), pair[0])?;
+        }
+
+        self.free_start = list.first().copied().unwrap_or(0);
+        self.free_end = list.last().copied().unwrap_or(0);
+        self.file.truncate(self.real_size)?;
+        self.torn = None;
+        self.flush()
+    }
+
+    /// 截断轨道尾部的连续失效分片
+    ///
+    /// `list`必须按照偏移排序，
+    /// 被截断的分片从`list`中移除，
+    /// 只修改轨道长度，不截断文件，
+    /// 返回是否有分片被截断
+    fn truncate_tail(&mut self, list: &mut Vec<u64>) -> Result<bool> {
+        let chunk_size = self.options.chunk_size;
+        let mut truncated = false;
+        while let Some(offset) = list.last().copied() {
+            if offset + chunk_size != self.real_size {
+                break;
+            }
+
+            // 截断的位置以后会从尾部重新分配，
+            // 新分片的代数必须大于截断分片的代数
+            let generation = match self.reused.remove(&offset) {
+                Some(generation) => Some(generation),
+                None => self.stored_generation(offset)?.map(|x| x.wrapping_add(1)),
+            };
+
+            if let Some(generation) = generation {
+                self.generation = self.generation.max(generation);
+            }
+
+            self.real_size -= chunk_size;
+            self.size -= chunk_size;
+            self.dirty = true;
+            truncated = true;
+            list.pop();
+        }
+
+        Ok(truncated)
+    }
+
+    /// 写入分片
+    ///
+    /// 写入单个分片数据到磁盘文件，
+    /// 分片代数由轨道内部维护，
+    /// 开启`verify_writes`时写入头部分片之后读回校验，
+    /// 读回的内容不一致时返回`KernelError::WriteMismatch`
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let index = track.alloc().unwrap().unwrap();
+    /// track.write(None, true, b"hello", index).unwrap();
+    /// ```
+    pub fn write(&mut self, next: Option<(u16, u64)>, head: bool, data: &[u8], index: u64) -> Result<()> {
+        let packet = self.encode(next, head, data, index)?;
+        self.file.write(&packet, index)?;
+        if !(head && self.options.verify_writes) {
+            return Ok(());
+        }
+
+        self.file.flush()?;
+        self.file.intact_read(&mut self.buffer, index)?;
+        match self.buffer[..] == packet[..] {
+            false => Err(KernelError::WriteMismatch(self.id, index).into()),
+            true => Ok(()),
+        }
+    }
+
+    /// 编码分片
+    ///
+    /// 和`write`相同的方式编码分片但是不写入，
+    /// 由外部使用独立的文件句柄写入
+    pub fn encode(&mut self, next: Option<(u16, u64)>, head: bool, data: &[u8], index: u64) -> Result<Bytes> {
+        self.validate_offset(index)?;
+        let generation = self.reused.remove(&index).unwrap_or(self.generation);
+        let flags = match head {
+            true => FLAG_HEAD,
+            false => 0,
+        };
+
+        let chunk = Chunk { next, generation, flags, hits: 0, accessed: 0, data };
+        Ok(self.chunk.encoder(&chunk))
+    }
+
+    /// 写入结束
+    ///
+    /// 当数据流写入完成的时候，
+    /// 将状态同步到磁盘文件，
+    /// 这是一个必要的操作，
+    /// 但是不强制什么时候调用，
+    /// 不过一定要在关闭实例之前调用一次
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use super::{Track, KernelOptions};
+    /// use std::rc::Rc;
+    ///
+    /// let options = Rc::new(KernelOptions::from(
+    ///     Path::new("./.static"), 
+    ///     1024 * 1024 * 1024 * 1
+    /// ));
+    ///
+    /// let mut track = Track::new(0, options).unwrap();
+    /// track.init().unwrap();
+    ///
+    /// let index = track.alloc().unwrap().unwrap();
+    /// track.write(None, true, b"hello", index).unwrap();
+    /// track.flush().unwrap();
+    /// ```
+    pub fn flush(&mut self) -> Result<()> {
+        self.dirty = false;
+        self.write_header()?;
+        self.file.flush()
+    }
+
+    /// 检查轨道头是否有未保存的修改
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// 遍历失效链表
+    ///
+    /// 收集所有失效分片的偏移，
+    /// 超过`limit`个分片时放弃遍历并返回`None`
+    #[rustfmt::skip]
+    fn walk_free(&mut self, limit: u64) -> Result<Option<Vec<u64>>> {
+        self.rebuild_bitmap(None)?;
+        if let Some(bitmap) = &self.bitmap {
+            let bits = bitmap.bits();
+            return Ok(Some(bits.into_iter().map(|x| self.bit_offset(x)).collect()));
+        }
+
+        if self.free_start == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        let mut list = vec![self.free_start];
+        let mut buffer = [0u8; 8];
+    loop {
+        if list.len() as u64 > limit {
+            return Ok(None);
+        }
+
+        let offset = *list.last().unwrap();
+        if offset == self.free_end {
+            break;
+        }
+
+        self.file.read(&mut buffer, offset)?;
+        list.push(u64::from_be_bytes(buffer));
+    }
+
+        Ok(Some(list))
+    }
+
+    /// 读取完整的缓冲区
+    ///
+    /// 文件长度不足时返回`KernelError::ShortRead`，
+    /// 不会解码不完整的分片
+    fn read_full(&mut self, size: usize, offset: u64) -> Result<()> {
+        match self.file.intact_read(&mut self.buffer[..size], offset) {
+            Err(e) if is_eof(&e) => Err(KernelError::ShortRead(self.id, offset).into()),
+            result => result,
+        }
+    }
+
+    /// 轨道文件路径
+    fn track_path(id: u16, options: &KernelOptions) -> PathBuf {
+        let path: &Path = options.path.as_ref();
+        path.join(format!("{}.track", id))
+    }
+
+    /// 位图文件路径
+    fn bitmap_path(&self) -> PathBuf {
+        let path: &Path = self.options.path.as_ref();
+        path.join(format!("{}.free", self.id))
+    }
+
+    /// 分片位置转换为位图中的位
+    fn offset_bit(&self, offset: u64) -> u64 {
+        (offset - TRACK_HEADER_SIZE) / self.options.chunk_size
+    }
+
+    /// 位图中的位转换为分片位置
+    fn bit_offset(&self, bit: u64) -> u64 {
+        TRACK_HEADER_SIZE + bit * self.options.chunk_size
+    }
+
+    /// 创建默认文件头
+    ///
+    /// 将默认的失效块头索引和尾部索引写入到磁盘文件,
+    /// 并初始化文件长度状态
+    fn default_header(&mut self) -> Result<()> {
+        self.real_size = TRACK_HEADER_SIZE;
+        self.size = TRACK_HEADER_SIZE;
+        self.write_header()
+    }
+
+    /// 写入文件头
+    ///
+    /// 依次写入文件标识，格式版本，尾部分片代数，
+    /// 失效块头索引和尾部索引，文件长度，以及轨道状态，
+    /// 剩余部分保留给以后的版本
+    fn write_header(&mut self) -> Result<()> {
+        let mut packet = BytesMut::with_capacity(TRACK_HEADER_SIZE as usize);
+        packet.put_slice(TRACK_MAGIC);
+        packet.put_u16(TRACK_VERSION);
+        packet.put_u16(self.generation);
+        packet.put_u64(self.free_start);
+        packet.put_u64(self.free_end);
+        packet.put_u64(self.size);
+        packet.put_u16(if self.closed { TRACK_CLOSED } else { 0 });
+        packet.resize(TRACK_HEADER_SIZE as usize, 0);
+        self.file.write(&packet, 0)
+    }
+
+    /// 读取分片已经保存的代数
+    ///
+    /// 分片还没有写入磁盘时返回空
+    fn stored_generation(&mut self, offset: u64) -> Result<Option<u16>> {
+        let mut buffer = [0u8; 2];
+        Ok(match self.file.read(&mut buffer, offset + 14)? {
+            2 => Some(u16::from_be_bytes(buffer)),
+            _ => None,
+        })
+    }
+
+    /// 读取文件头
+    ///
+    /// 从磁盘文件中读取失效块头索引和尾部索引，
+    /// 这是必要的操作，轨道实例化的时候必须要
+    /// 从文件中恢复上次的状态，
+    /// 文件头不完整或者格式版本不同时返回`KernelError::InvalidTrack`，
+    /// 包括没有写入文件头的空文件，
+    /// 旧版本的轨道在初始化之前已经由`legacy::isolate`隔离
+    fn read_header(&mut self) -> Result<()> {
+        if self.real_size < TRACK_HEADER_SIZE {
+            return Err(KernelError::InvalidTrack(self.id).into());
+        }
+
+        // 从文件中读取头部
+        let mut buffer = [0u8; TRACK_HEADER_SIZE as usize];
+        self.file.intact_read(&mut buffer, 0)?;
+        let mut packet = Bytes::from(buffer.to_vec());
+        let magic = packet.split_to(TRACK_MAGIC.len());
+        if magic[..] != TRACK_MAGIC[..] || packet.get_u16() != TRACK_VERSION {
+            return Err(KernelError::InvalidTrack(self.id).into());
+        }
+
+        // 将状态同步到实例内部
+        self.generation = packet.get_u16();
+        self.free_start = packet.get_u64();
+        self.free_end = packet.get_u64();
+        self.size = packet.get_u64();
+        self.closed = packet.get_u16() & TRACK_CLOSED != 0;
+        
+        Ok(())
+    }
+}
+
+/// 检查错误是否为文件提前结束
+fn is_eof(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+        Some(std::io::ErrorKind::UnexpectedEof)
+    )
+}
+
+impl Drop for Track {
+    fn drop(&mut self) {
+        // 没有初始化成功的轨道不写入任何内容，
+        // 避免覆盖无法识别的轨道文件
+        if self.size == 0 {
+            return;
+        }
+
+        // 位图没有重建完成时保持未关闭状态，
+        // 下次打开时重新扫描
+        self.closed = self.rebuild.is_none();
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Track, FreeListPersistence, KernelError, KernelOptions, TRACK_HEADER_SIZE};
+    use std::convert::TryInto;
+    use std::time::Duration;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    fn options(path: &Path) -> Rc<KernelOptions> {
+        Rc::new(KernelOptions::from(path.to_str().unwrap().to_string(), 1024 * 1024))
+    }
+
+    #[test]
+    fn rejects_track_without_current_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.track");
+        for content in [vec![0u8; 24], vec![0u8; TRACK_HEADER_SIZE as usize]] {
+            std::fs::write(&path, content).unwrap();
+            let mut track = Track::new(1, options(dir.path())).unwrap();
+            let error = track.init().unwrap_err();
+            assert_eq!(error.downcast_ref::<KernelError>(), Some(&KernelError::InvalidTrack(1)));
+        }
+    }
+
+    #[test]
+    fn validate_offset_requires_alignment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut track = Track::create(1, options(dir.path())).unwrap();
+        track.init().unwrap();
+        for offset in [40, 40 + 4096, 40 + 4096 * 100] {
+            assert!(track.validate_offset(offset).is_ok());
+        }
+
+        for offset in [0, 41, 40 + 4095, 40 + 4096 + 1] {
+            let error = track.validate_offset(offset).unwrap_err();
+            assert_eq!(error.downcast_ref::<KernelError>(), Some(&KernelError::UnalignedOffset(offset)));
+        }
+    }
+
+    #[test]
+    fn offsets_inside_header_are_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut track = Track::create(1, options(dir.path())).unwrap();
+        track.init().unwrap();
+        for offset in [1, 3, TRACK_HEADER_SIZE - 1] {
+            let error = track.read(offset).err().unwrap();
+            assert_eq!(error.downcast_ref::<KernelError>(), Some(&KernelError::CorruptChain(offset)));
+        }
+    }
+
+    #[test]
+    fn verified_write_reads_back_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = KernelOptions::from(dir.path().to_str().unwrap().to_string(), 1024 * 1024);
+        options.verify_writes = true;
+        let mut track = Track::create(1, Rc::new(options)).unwrap();
+        track.init().unwrap();
+        let head = track.alloc().unwrap().unwrap();
+        let body = track.alloc().unwrap().unwrap();
+        track.write(Some((1, body)), true, b"hello", head).unwrap();
+        track.write(None, false, b"world", body).unwrap();
+
+        assert!(track.header(head).unwrap().is_head());
+        let chunk = track.read(head).unwrap();
+        assert_eq!(chunk.next, Some((1, body)));
+        assert_eq!(chunk.data, b"hello");
+        assert_eq!(track.read(body).unwrap().data, b"world");
+    }
+
+    fn bitmap_track(path: &Path, free_bitmap: bool, chunks: u64) -> Track {
+        let mut options = KernelOptions::from(path.to_str().unwrap().to_string(), 40 + 4096 * chunks);
+        options.free_bitmap = free_bitmap;
+        let mut track = match path.join("1.track").exists() {
+            true => Track::new(1, Rc::new(options)).unwrap(),
+            false => Track::create(1, Rc::new(options)).unwrap(),
+        };
+
+        track.init().unwrap();
+        track
+    }
+
+    #[test]
+    fn bitmap_matches_free_list() {
+        let list_dir = tempfile::tempdir().unwrap();
+        let bitmap_dir = tempfile::tempdir().unwrap();
+        let mut list = bitmap_track(list_dir.path(), false, 64);
+        let mut bitmap = bitmap_track(bitmap_dir.path(), true, 64);
+        let mut live: Vec<(u64, u64)> = Vec::new();
+        let mut seed = 12345u64;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        // 同样的分配和删除序列下，
+        // 两种方式的有效分片、失效分片、文件长度和分配结果一致，
+        // 失效链表按照删除顺序复用而位图按照偏移顺序复用，
+        // 所以每次删除之后整理两边，让失效链表也按照偏移排序
+        for i in 0..2000u64 {
+            if !live.is_empty() && random() % 3 == 0 {
+                let (a, b) = live.swap_remove((random() % live.len() as u64) as usize);
+                list.remove(a).unwrap();
+                bitmap.remove(b).unwrap();
+                let mut budget = (u64::MAX, u64::MAX);
+                assert!(list.tidy(&mut budget.0).unwrap());
+                assert!(bitmap.tidy(&mut budget.1).unwrap());
+            } else {
+                match (list.alloc().unwrap(), bitmap.alloc().unwrap()) {
+                    (Some(a), Some(b)) => {
+                        list.write(None, true, &i.to_be_bytes(), a).unwrap();
+                        bitmap.write(None, true, &i.to_be_bytes(), b).unwrap();
+                        live.push((a, b));
+                    },
+                    (None, None) => (),
+                    other => panic!("allocation diverged: {:?}", other),
+                }
+            }
+
+            let mut free = (list.free_list().unwrap(), bitmap.free_list().unwrap());
+            free.0.sort_unstable();
+            free.1.sort_unstable();
+            assert_eq!(free.0, free.1);
+
+            // 有效分片和失效分片正好覆盖整个轨道
+            let mut used = live.iter().map(|x| x.1).collect::<Vec<_>>();
+            assert!(live.iter().all(|(a, b)| a == b), "live chunks diverged");
+            used.extend(free.1);
+            used.sort_unstable();
+            assert_eq!(used, bitmap.offsets().collect::<Vec<_>>());
+            assert_eq!(list.file.stat().unwrap().len(), bitmap.file.stat().unwrap().len());
+        }
+
+        for (a, b) in live {
+            assert_eq!(list.read(a).unwrap().data, bitmap.read(b).unwrap().data);
+        }
+    }
+
+    #[test]
+    fn bitmap_is_rebuilt_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut track = bitmap_track(dir.path(), true, 8);
+        let offsets = (0..6).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+        for offset in offsets.iter() {
+            track.write(None, true, b"data", *offset).unwrap();
+        }
+
+        track.flush().unwrap();
+        track.remove(offsets[1]).unwrap();
+        track.remove(offsets[3]).unwrap();
+
+        // 没有正常关闭轨道，
+        // 并且位图文件丢失了修改
+        std::mem::forget(track);
+        std::fs::write(dir.path().join("1.free"), [0u8; 8]).unwrap();
+        let mut track = bitmap_track(dir.path(), true, 8);
+        let mut free = track.free_list().unwrap();
+        free.sort_unstable();
+        assert_eq!(free, vec![offsets[1], offsets[3]]);
+        assert_eq!(track.heads().unwrap(), vec![offsets[0], offsets[2], offsets[4], offsets[5]]);
+    }
+
+    #[test]
+    fn bitmap_rebuild_is_deferred_past_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunks = 4096;
+        let mut track = bitmap_track(dir.path(), true, chunks);
+        let offsets = (0..chunks).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+        for offset in offsets.iter() {
+            track.write(None, true, b"data", *offset).unwrap();
+        }
+
+        track.flush().unwrap();
+        track.remove(offsets[7]).unwrap();
+
+        // 没有正常关闭轨道，
+        // 并且位图文件丢失了修改
+        std::mem::forget(track);
+        std::fs::write(dir.path().join("1.free"), [0u8; 8]).unwrap();
+
+        let mut options = KernelOptions::from(dir.path().to_str().unwrap().to_string(), 40 + 4096 * chunks);
+        options.free_bitmap = true;
+        options.bitmap_rebuild_budget = Some(Duration::ZERO);
+        let options = Rc::new(options);
+
+        // 没有预算时初始化不扫描分片，
+        // 读取不需要等待重建
+        let mut track = Track::new(1, options.clone()).unwrap();
+        track.init().unwrap();
+        assert!(track.is_rebuilding());
+        assert_eq!(track.read(offsets[0]).unwrap().data, b"data");
+        assert!(track.is_rebuilding());
+
+        // 重建完成之前关闭，下次打开时重新扫描
+        drop(track);
+        let mut track = Track::new(1, options).unwrap();
+        track.init().unwrap();
+        assert!(track.is_rebuilding());
+
+        // 轨道已经写满，
+        // 分配失效分片之前完成重建
+        assert_eq!(track.alloc().unwrap(), Some(offsets[7]));
+        assert!(!track.is_rebuilding());
+        assert_eq!(track.alloc().unwrap(), None);
+    }
+
+    #[test]
+    fn missing_bitmap_is_rebuilt_after_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut track = bitmap_track(dir.path(), true, 4);
+        let offsets = (0..4).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+        for offset in offsets.iter() {
+            track.write(None, true, b"data", *offset).unwrap();
+        }
+
+        track.remove(offsets[1]).unwrap();
+        drop(track);
+
+        // 正常关闭之后位图文件被删除
+        std::fs::remove_file(dir.path().join("1.free")).unwrap();
+        let mut track = bitmap_track(dir.path(), true, 4);
+        assert_eq!(track.free_list().unwrap(), vec![offsets[1]]);
+        assert_eq!(track.alloc().unwrap(), Some(offsets[1]));
+    }
+
+    #[test]
+    fn free_list_persistence_policy() {
+        for policy in [FreeListPersistence::Eager, FreeListPersistence::Lazy] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut options = KernelOptions::from(dir.path().to_str().unwrap().to_string(), 1024 * 1024);
+            options.free_list_persistence = policy;
+            let mut track = Track::create(1, Rc::new(options)).unwrap();
+            track.init().unwrap();
+            let offsets = (0..3).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+            for offset in offsets.iter() {
+                track.write(None, true, b"data", *offset).unwrap();
+            }
+
+            track.flush().unwrap();
+            track.remove(offsets[1]).unwrap();
+
+            // 延迟保存时轨道头中的失效链表在`flush`之后才更新
+            let stored = || {
+                let header = std::fs::read(dir.path().join("1.track")).unwrap();
+                u64::from_be_bytes(header[8..16].try_into().unwrap())
+            };
+
+            let eager = policy == FreeListPersistence::Eager;
+            assert_eq!(track.is_dirty(), !eager);
+            assert_eq!(stored() == offsets[1], eager);
+            track.flush().unwrap();
+            assert!(!track.is_dirty());
+            assert_eq!(stored(), offsets[1]);
+        }
+    }
+
+    #[test]
+    fn alloc_respects_max_track_file_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = KernelOptions::from(dir.path().to_str().unwrap().to_string(), 1024 * 1024);
+        options.max_track_file_bytes = Some(40 + 4096 * 3 + 100);
+        let mut track = Track::create(1, Rc::new(options)).unwrap();
+        track.init().unwrap();
+        for _ in 0..3 {
+            let offset = track.alloc().unwrap().unwrap();
+            track.write(None, true, b"data", offset).unwrap();
+        }
+
+        assert_eq!(track.alloc().unwrap(), None);
+        assert_eq!(track.file.stat().unwrap().len(), 40 + 4096 * 3);
+    }
+
+    #[test]
+    fn torn_tail_is_short_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut track = Track::create(1, options(dir.path())).unwrap();
+        track.init().unwrap();
+        let offsets = (0..3).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+        for offset in offsets.iter() {
+            track.write(None, true, b"data", *offset).unwrap();
+        }
+
+        drop(track);
+        let path = dir.path().join("1.track");
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(offsets[2] + 100).unwrap();
+
+        // 不完整的尾部分片返回`ShortRead`，
+        // 更远的位置仍然是悬空指针
+        let mut track = Track::new(1, options(dir.path())).unwrap();
+        track.init().unwrap();
+        let short = track.read(offsets[2]).err().unwrap();
+        assert_eq!(short.downcast_ref::<KernelError>(), Some(&KernelError::ShortRead(1, offsets[2])));
+        let dangling = track.header(offsets[2] + 4096).unwrap_err();
+        assert_eq!(dangling.downcast_ref::<KernelError>(), Some(&KernelError::DanglingPointer(1, offsets[2] + 4096)));
+        assert_eq!(track.read(offsets[1]).unwrap().data, b"data");
+
+        // 该位置重新分配之后可以正常读写
+        assert_eq!(track.alloc().unwrap(), Some(offsets[2]));
+        track.write(None, true, b"again", offsets[2]).unwrap();
+        assert_eq!(track.read(offsets[2]).unwrap().data, b"again");
+    }
+
+    #[test]
+    fn free_truncates_tail_and_keeps_holes() {
+        for free_bitmap in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut track = bitmap_track(dir.path(), free_bitmap, 16);
+            let offsets = (0..5).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+            track.write(None, true, b"data", offsets[0]).unwrap();
+            track.write(None, true, b"data", offsets[2]).unwrap();
+
+            // 尾部未使用的预分配分片直接截断，
+            // 中间的分片进入失效分片记录
+            track.free(vec![offsets[4], offsets[1], offsets[3]]).unwrap();
+            assert_eq!(track.file.stat().unwrap().len(), offsets[3]);
+            assert_eq!(track.free_list().unwrap(), vec![offsets[1]]);
+            drop(track);
+
+            let mut track = bitmap_track(dir.path(), free_bitmap, 16);
+            assert_eq!(track.free_list().unwrap(), vec![offsets[1]]);
+            assert_eq!(track.heads().unwrap(), vec![offsets[0], offsets[2]]);
+        }
+    }
+
+    #[test]
+    fn racing_creates_share_one_track() {
+        for _ in 0..20 {
+            let dir = tempfile::tempdir().unwrap();
+            let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let writers = (0..2u8).map(|i| {
+                let path = dir.path().to_path_buf();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let mut track = Track::create(1, options(&path)).unwrap();
+
+                    // 两个句柄依次写入，
+                    // 每次写入之前重新读取轨道状态
+                    for turn in 0..2u8 {
+                        barrier.wait();
+                        if turn == i {
+                            track.init().unwrap();
+                            let offset = track.alloc().unwrap().unwrap();
+                            assert_eq!(offset, TRACK_HEADER_SIZE + 4096 * i as u64);
+                            track.write(None, true, &[i], offset).unwrap();
+                            track.flush().unwrap();
+                        }
+                    }
+
+                    barrier.wait();
+                    track.init().unwrap();
+                    for other in 0..2u8 {
+                        let offset = TRACK_HEADER_SIZE + 4096 * other as u64;
+                        assert!(track.header(offset).unwrap().is_head());
+                        assert_eq!(track.read(offset).unwrap().data, &[other]);
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            for writer in writers {
+                writer.join().unwrap();
+            }
+
+            let names = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|x| x.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec!["1.track".to_string()]);
+        }
+    }
+
+    #[test]
+    fn create_over_short_file_is_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("1.track"), [0u8; 10]).unwrap();
+        let error = Track::create(1, options(dir.path())).err().unwrap();
+        assert_eq!(error.downcast_ref::<KernelError>(), Some(&KernelError::InvalidTrack(1)));
+    }
+
+    #[test]
+    fn free_run_histogram_buckets_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut track = Track::create(1, options(dir.path())).unwrap();
+        track.init().unwrap();
+        assert!(track.free_run_histogram().unwrap().is_empty());
+        let offsets = (0..12).map(|_| track.alloc().unwrap().unwrap()).collect::<Vec<_>>();
+        for offset in offsets.iter() {
+            track.write(None, true, b"data", *offset).unwrap();
+        }
+
+        // 失效分片的连续区间为[1, 2]、[4]、[6, 7, 8]和[10]
+        for i in [1usize, 2, 4, 8, 7, 6, 10] {
+            track.remove(offsets[i]).unwrap();
+        }
+
+        assert_eq!(track.free_run_histogram().unwrap(), vec![(1, 2), (2, 1), (3, 1)]);
     }
 }